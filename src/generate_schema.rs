@@ -22,4 +22,14 @@ fn main() {
     generate_schema_for_event_type::<UpsertCollection>("upsert_collection");
     generate_schema_for_event_type::<RemoveCollection>("remove_collection");
     generate_schema_for_event_type::<SearchImage>("search_image");
+    generate_schema_for_event_type::<SearchSimilar>("search_similar");
+    generate_schema_for_event_type::<BatchSearch>("batch_search");
+    generate_schema_for_event_type::<RangeSearch>("range_search");
+    generate_schema_for_event_type::<FederatedSearch>("federated_search");
+    generate_schema_for_event_type::<FindDuplicates>("find_duplicates");
+    generate_schema_for_event_type::<GetDuplicates>("get_duplicates");
+    generate_schema_for_event_type::<FitPca>("fit_pca");
+    generate_schema_for_event_type::<ClusterCollection>("cluster_collection");
+    generate_schema_for_event_type::<GetClusters>("get_clusters");
+    generate_schema_for_event_type::<GetClusterMembers>("get_cluster_members");
 }
@@ -0,0 +1,273 @@
+use image::imageops::{resize, FilterType};
+use image::{Rgb, RgbImage};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+// larger images are shrunk first, colour distributions barely change
+const MAX_SIDE: u32 = 256;
+// level 4 already has 256 cells of 16x16 pixels
+const MAX_PYRAMID_LEVELS: u32 = 4;
+const MAX_HISTOGRAM_BINS: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum ColorSpace {
+    // hue, saturation, value
+    #[default]
+    Hsv,
+    // CIELAB, perceptually uniform
+    Lab,
+}
+
+fn default_bins() -> [usize; 3] {
+    [8, 4, 4]
+}
+
+/// Handcrafted colour features: a joint histogram of the three channels of
+/// `color_space` with `bins` per channel, normalized to sum to 1. With
+/// `pyramid_levels` the image is also split in 2x2, 4x4... cells, each with
+/// its own histogram. With `moments` the mean, standard deviation and
+/// skewness of every channel are appended for every region.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ColorHistogram {
+    #[serde(default)]
+    pub color_space: ColorSpace,
+    #[serde(default = "default_bins")]
+    pub bins: [usize; 3],
+    #[serde(default)]
+    pub pyramid_levels: u32,
+    #[serde(default)]
+    pub moments: bool,
+}
+
+/// Channels scaled to [0, 1].
+fn to_color_space(pixel: &Rgb<u8>, color_space: ColorSpace) -> [f64; 3] {
+    let [r, g, b] = pixel.0.map(|c| c as f64 / 255.0);
+    match color_space {
+        ColorSpace::Hsv => {
+            let max = r.max(g).max(b);
+            let delta = max - r.min(g).min(b);
+            let hue = if delta == 0.0 {
+                0.0
+            } else if max == r {
+                ((g - b) / delta).rem_euclid(6.0)
+            } else if max == g {
+                (b - r) / delta + 2.0
+            } else {
+                (r - g) / delta + 4.0
+            };
+            let saturation = if max == 0.0 { 0.0 } else { delta / max };
+            [hue / 6.0, saturation, max]
+        }
+        ColorSpace::Lab => {
+            let linear = |c: f64| {
+                if c <= 0.04045 {
+                    c / 12.92
+                } else {
+                    ((c + 0.055) / 1.055).powf(2.4)
+                }
+            };
+            let (r, g, b) = (linear(r), linear(g), linear(b));
+            // XYZ relative to the D65 white point
+            let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+            let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+            let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+            let f = |t: f64| {
+                if t > 0.008856 {
+                    t.cbrt()
+                } else {
+                    7.787 * t + 16.0 / 116.0
+                }
+            };
+            let (fx, fy, fz) = (f(x), f(y), f(z));
+            let (l, a, b) = (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz));
+            [
+                (l / 100.0).clamp(0.0, 1.0),
+                ((a + 128.0) / 256.0).clamp(0.0, 1.0),
+                ((b + 128.0) / 256.0).clamp(0.0, 1.0),
+            ]
+        }
+    }
+}
+
+impl ColorHistogram {
+    /// Rejects configs giving empty or needlessly large feature vectors.
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.bins.contains(&0) {
+            return Err("Every channel of a colour histogram needs at least one bin".into());
+        }
+        let histogram: usize = self.bins.iter().product();
+        if histogram > MAX_HISTOGRAM_BINS {
+            return Err(format!(
+                "A colour histogram has at most {} bins, got {}",
+                MAX_HISTOGRAM_BINS, histogram
+            )
+            .into());
+        }
+        if self.pyramid_levels > MAX_PYRAMID_LEVELS {
+            return Err(format!(
+                "A colour histogram has at most {} pyramid levels, got {}",
+                MAX_PYRAMID_LEVELS, self.pyramid_levels
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn n_regions(&self) -> usize {
+        (0..=self.pyramid_levels)
+            .map(|level| 1 << (2 * level))
+            .sum()
+    }
+
+    fn features_per_region(&self) -> usize {
+        let histogram: usize = self.bins.iter().product();
+        histogram + if self.moments { 9 } else { 0 }
+    }
+
+    /// Length of the feature vectors.
+    pub fn dimension(&self) -> usize {
+        self.n_regions() * self.features_per_region()
+    }
+
+    pub fn extract_features(&self, image: &RgbImage) -> Vec<f64> {
+        let (width, height) = image.dimensions();
+        let scale = (MAX_SIDE as f64 / width.max(height).max(1) as f64).min(1.0);
+        let small = resize(
+            image,
+            ((width as f64 * scale) as u32).max(1),
+            ((height as f64 * scale) as u32).max(1),
+            FilterType::Triangle,
+        );
+        let (width, height) = small.dimensions();
+        let colors: Vec<[f64; 3]> = small
+            .pixels()
+            .map(|p| to_color_space(p, self.color_space))
+            .collect();
+
+        let mut features = Vec::with_capacity(self.dimension());
+        for level in 0..=self.pyramid_levels {
+            let cells = 1u32 << level;
+            for cell_y in 0..cells {
+                for cell_x in 0..cells {
+                    // cells may be empty for tiny images
+                    let xs = (cell_x * width / cells)..((cell_x + 1) * width / cells);
+                    let ys = (cell_y * height / cells)..((cell_y + 1) * height / cells);
+                    let region: Vec<&[f64; 3]> = ys
+                        .flat_map(|y| xs.clone().map(move |x| (x, y)))
+                        .map(|(x, y)| &colors[(y * width + x) as usize])
+                        .collect();
+                    features.extend(self.histogram(&region));
+                    if self.moments {
+                        features.extend(moments(&region));
+                    }
+                }
+            }
+        }
+        features
+    }
+
+    fn histogram(&self, region: &[&[f64; 3]]) -> Vec<f64> {
+        let mut histogram = vec![0.0; self.bins.iter().product()];
+        for color in region {
+            let index = color.iter().zip(&self.bins).fold(0, |index, (&value, &n)| {
+                index * n + ((value * n as f64) as usize).min(n - 1)
+            });
+            histogram[index] += 1.0;
+        }
+        histogram
+            .into_iter()
+            .map(|count| count / region.len().max(1) as f64)
+            .collect()
+    }
+}
+
+/// Mean, standard deviation and cube root of the third central moment of
+/// every channel.
+fn moments(region: &[&[f64; 3]]) -> Vec<f64> {
+    let n = region.len().max(1) as f64;
+    let mut result = Vec::with_capacity(9);
+    for channel in 0..3 {
+        let mean = region.iter().map(|c| c[channel]).sum::<f64>() / n;
+        let central = |power: i32| {
+            region
+                .iter()
+                .map(|c| (c[channel] - mean).powi(power))
+                .sum::<f64>()
+                / n
+        };
+        result.extend([mean, central(2).sqrt(), central(3).cbrt()]);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_histogram() {
+        let red = Rgb([255, 0, 0]);
+        let blue = Rgb([0, 0, 255]);
+        let hsv = ColorHistogram {
+            color_space: ColorSpace::Hsv,
+            bins: [6, 2, 2],
+            pyramid_levels: 0,
+            moments: false,
+        };
+        let features = hsv.extract_features(&RgbImage::from_pixel(10, 10, red));
+        assert_eq!(features.len(), hsv.dimension());
+        assert_eq!(features.len(), 24);
+        // hue 0, full saturation and value
+        assert_eq!(features[3], 1.0);
+        assert_eq!(features.iter().sum::<f64>(), 1.0);
+
+        // left half red, right half blue
+        let half = RgbImage::from_fn(8, 8, |x, _| if x < 4 { red } else { blue });
+        let pyramid = ColorHistogram {
+            pyramid_levels: 1,
+            moments: true,
+            ..hsv.clone()
+        };
+        let features = pyramid.extract_features(&half);
+        assert_eq!(pyramid.dimension(), 5 * (24 + 9));
+        assert_eq!(features.len(), pyramid.dimension());
+        let regions: Vec<&[f64]> = features.chunks(33).collect();
+        assert_eq!(regions[0][3], 0.5);
+        // top left and top right cells
+        assert_eq!(regions[1][3], 1.0);
+        assert_eq!(regions[2][3], 0.0);
+        // a uniform cell has the value of its colour as mean and no deviation
+        assert_eq!(
+            &regions[1][24..],
+            &[0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0]
+        );
+
+        let lab = ColorHistogram {
+            color_space: ColorSpace::Lab,
+            bins: [4, 4, 4],
+            pyramid_levels: 0,
+            moments: true,
+        };
+        let white = lab.extract_features(&RgbImage::from_pixel(4, 4, Rgb([255, 255, 255])));
+        // lightness 100 in the last bin
+        assert_eq!(white[..64].iter().position(|&v| v == 1.0).unwrap() / 16, 3);
+        assert!((white[64] - 1.0).abs() < 1e-3);
+        assert!((white[67] - 0.5).abs() < 1e-3);
+
+        assert!(lab.check().is_ok());
+        let invalid = |histogram: ColorHistogram| histogram.check().is_err();
+        assert!(invalid(ColorHistogram {
+            bins: [4, 0, 4],
+            ..lab.clone()
+        }));
+        assert!(invalid(ColorHistogram {
+            bins: [32, 16, 16],
+            ..lab.clone()
+        }));
+        assert!(invalid(ColorHistogram {
+            pyramid_levels: 5,
+            ..lab
+        }));
+    }
+}
@@ -0,0 +1,191 @@
+use image::imageops::{grayscale, resize, FilterType};
+use image::{GrayImage, RgbImage};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::f64::consts::PI;
+
+// the DCT of pHash is computed on an image this many times larger than the hash
+const PHASH_SCALE: u32 = 4;
+// 1024 bits, far more than needed to tell copies apart
+const MAX_HASH_SIZE: u32 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum HashAlgorithm {
+    // pixels brighter than the mean
+    AHash,
+    // pixels brighter than their right neighbor
+    DHash,
+    // low frequencies of the DCT above their median
+    PHash,
+}
+
+fn default_hash_size() -> u32 {
+    8
+}
+
+/// A perceptual hash of the image, `hash_size` squared bits given as a vector
+/// of zeros and ones. Resized, re-encoded or slightly edited copies of an
+/// image have hashes which differ in only a few bits.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PerceptualHash {
+    pub algorithm: HashAlgorithm,
+    #[serde(default = "default_hash_size")]
+    pub hash_size: u32,
+}
+
+fn bits(values: &[f64], threshold: f64) -> Vec<f64> {
+    values
+        .iter()
+        .map(|&value| if value > threshold { 1.0 } else { 0.0 })
+        .collect()
+}
+
+fn pixels(image: &RgbImage, width: u32, height: u32) -> GrayImage {
+    resize(&grayscale(image), width, height, FilterType::Triangle)
+}
+
+impl PerceptualHash {
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.hash_size == 0 || self.hash_size > MAX_HASH_SIZE {
+            return Err(format!(
+                "The hash size must be between 1 and {}, got {}",
+                MAX_HASH_SIZE, self.hash_size
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Number of bits of the hash.
+    pub fn dimension(&self) -> usize {
+        let size = self.hash_size as usize;
+        size * size
+    }
+
+    pub fn hash(&self, image: &RgbImage) -> Vec<f64> {
+        let size = self.hash_size;
+        match self.algorithm {
+            HashAlgorithm::AHash => {
+                let values: Vec<f64> = pixels(image, size, size)
+                    .pixels()
+                    .map(|p| p[0] as f64)
+                    .collect();
+                let mean = values.iter().sum::<f64>() / values.len() as f64;
+                bits(&values, mean)
+            }
+            HashAlgorithm::DHash => {
+                let small = pixels(image, size + 1, size);
+                let mut hash = Vec::with_capacity(self.dimension());
+                for y in 0..size {
+                    for x in 0..size {
+                        let (left, right) =
+                            (small.get_pixel(x, y)[0], small.get_pixel(x + 1, y)[0]);
+                        hash.push(if left > right { 1.0 } else { 0.0 });
+                    }
+                }
+                hash
+            }
+            HashAlgorithm::PHash => {
+                let n = size * PHASH_SCALE;
+                let small = pixels(image, n, n);
+                let low_frequencies = dct_low_frequencies(&small, size as usize);
+                let mut sorted = low_frequencies.clone();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                let median = sorted[sorted.len() / 2];
+                bits(&low_frequencies, median)
+            }
+        }
+    }
+}
+
+/// The `size` by `size` lowest frequencies of the 2D DCT-II of a square
+/// image, row by row.
+fn dct_low_frequencies(image: &GrayImage, size: usize) -> Vec<f64> {
+    let n = image.width() as usize;
+    let basis: Vec<Vec<f64>> = (0..size)
+        .map(|u| {
+            (0..n)
+                .map(|x| ((2 * x + 1) as f64 * u as f64 * PI / (2 * n) as f64).cos())
+                .collect()
+        })
+        .collect();
+    // transform the rows first, then the columns
+    let rows: Vec<Vec<f64>> = (0..n)
+        .map(|y| {
+            basis
+                .iter()
+                .map(|b| {
+                    (0..n)
+                        .map(|x| image.get_pixel(x as u32, y as u32)[0] as f64 * b[x])
+                        .sum()
+                })
+                .collect()
+        })
+        .collect();
+    let mut coefficients = Vec::with_capacity(size * size);
+    for b in &basis {
+        for u in 0..size {
+            coefficients.push(rows.iter().zip(b).map(|(row, b)| row[u] * b).sum());
+        }
+    }
+    coefficients
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_transform::functions::read_rgb_image;
+    use image::imageops::{brighten, crop_imm};
+
+    fn hamming(a: &[f64], b: &[f64]) -> usize {
+        a.iter().zip(b).filter(|(x, y)| x != y).count()
+    }
+
+    #[test]
+    fn test_perceptual_hash() {
+        let cat = read_rgb_image("images/cat.jpeg");
+        let (width, height) = cat.dimensions();
+        let resized = resize(&cat, width / 2, height / 2, FilterType::Lanczos3);
+        let brighter = brighten(&cat, 20);
+        let cropped = crop_imm(&cat, 0, 0, width / 2, height).to_image();
+
+        for algorithm in &[
+            HashAlgorithm::AHash,
+            HashAlgorithm::DHash,
+            HashAlgorithm::PHash,
+        ] {
+            let hasher = PerceptualHash {
+                algorithm: *algorithm,
+                hash_size: 8,
+            };
+            let hash = hasher.hash(&cat);
+            assert_eq!(hash.len(), hasher.dimension());
+            assert!(hash.iter().all(|&bit| bit == 0.0 || bit == 1.0));
+            assert!(hash.contains(&0.0) && hash.contains(&1.0));
+            assert!(
+                hamming(&hash, &hasher.hash(&resized)) <= 4,
+                "{:?}",
+                algorithm
+            );
+            assert!(
+                hamming(&hash, &hasher.hash(&brighter)) <= 4,
+                "{:?}",
+                algorithm
+            );
+            assert!(
+                hamming(&hash, &hasher.hash(&cropped)) > 10,
+                "{:?}",
+                algorithm
+            );
+        }
+
+        for &(hash_size, valid) in &[(0, false), (1, true), (32, true), (33, false)] {
+            let hasher = PerceptualHash {
+                algorithm: HashAlgorithm::DHash,
+                hash_size,
+            };
+            assert_eq!(hasher.check().is_ok(), valid, "{}", hash_size);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 pub mod architectures;
+pub mod color;
 pub mod functions;
+pub mod hashing;
 pub mod models;
 pub mod pipeline;
 pub mod utils;
@@ -47,37 +47,55 @@ pub struct ModelConfig {
 pub struct LoadedModel {
     pub config: ModelConfig,
     pub model: TractSimplePlan,
+    // the same model taking any number of images at once, only used for
+    // batches. None when its graph has a batch size of 1 baked in
+    pub batch_model: Option<TractSimplePlan>,
 }
 
 impl LoadedModel {
     pub fn new_from_architecture(architecture: ModelArchitecture) -> Self {
         let config = load_model_config(architecture);
-        let model = LoadedModel::load_model(&config);
-        Self { config, model }
+        LoadedModel::new_from_config(config)
     }
 
     pub fn new_from_config(config: ModelConfig) -> Self {
-        let model = LoadedModel::load_model(&config);
-        Self { config, model }
+        let model = LoadedModel::read_model(&config);
+        LoadedModel::new_from_inference_model(config, model)
     }
 
-    pub fn load_model(config: &ModelConfig) -> TractSimplePlan {
+    pub fn new_from_inference_model(config: ModelConfig, model: InferenceModel) -> Self {
+        let batch = model.symbol_table.sym("N");
+        let batch_model = match LoadedModel::load_model(&config, model.clone(), batch.into()) {
+            Ok(batch_model) => Some(batch_model),
+            Err(e) => {
+                println!(
+                    "Cannot run the model on batches, using one image at a time: {}",
+                    e
+                );
+                None
+            }
+        };
+        let model = LoadedModel::load_model(&config, model, 1.into()).expect("Cannot load model");
+        Self {
+            config,
+            model,
+            batch_model,
+        }
+    }
+
+    /// Downloads the model file unless it is already there and reads it.
+    pub fn read_model(config: &ModelConfig) -> InferenceModel {
         let name = config.model_name.clone();
         let url = config.model_url.clone();
         let extension = config.model_type.to_extension();
         let filename = model_filename(&name, &extension);
         if !Path::new(&filename).exists() {
             println!("Downloading model file");
-            save_file_get(&url, &filename);
+            save_file_get(&url, &filename).expect("Cannot download model");
         } else {
             println!("Skipping download");
         }
 
-        let input_shape = match config.channels {
-            Channels::CWH => tvec!(1, 3, config.image_size.width, config.image_size.height),
-            Channels::WHC => tvec!(1, config.image_size.width, config.image_size.height, 3),
-        };
-
         // let mut model = match config.model_type {
         //     ModelType::NNEF => {
         //         tract_nnef::nnef()
@@ -95,19 +113,48 @@ impl LoadedModel {
         //     }
         // };
 
-        let mut model = tract_onnx::onnx()
+        tract_onnx::onnx()
             .model_for_path(&filename)
             .expect("Cannot read model")
-            .with_input_fact(0, InferenceFact::dt_shape(f32::datum_type(), input_shape))
-            .unwrap();
+    }
+
+    /// Optimizes the model for inputs of `batch` images.
+    pub fn load_model(
+        config: &ModelConfig,
+        model: InferenceModel,
+        batch: TDim,
+    ) -> TractResult<TractSimplePlan> {
+        let (width, height) = (config.image_size.width, config.image_size.height);
+        let input_shape: TVec<TDim> = match config.channels {
+            Channels::CWH => tvec!(batch, 3.into(), width.into(), height.into()),
+            Channels::WHC => tvec!(batch, width.into(), height.into(), 3.into()),
+        };
+        let mut model =
+            model.with_input_fact(0, InferenceFact::dt_shape(f32::datum_type(), input_shape))?;
 
         if let Some(layer_name) = config.layer_name.clone() {
             let node_names: Vec<&str> = model.node_names().collect::<Vec<&str>>().clone();
             println!("Available nodes {:?}", node_names);
-            model = model.with_output_names(vec![layer_name]).unwrap()
+            model = model.with_output_names(vec![layer_name])?
         }
 
-        model.into_optimized().unwrap().into_runnable().unwrap()
+        model.into_optimized()?.into_runnable()
+    }
+
+    /// Length of the feature vectors, `None` if the output shape of the model
+    /// is only known once it runs.
+    pub fn dimension(&self) -> Option<usize> {
+        let fact = self.model.model().output_fact(0).ok()?;
+        Some(fact.shape.as_concrete()?.iter().product())
+    }
+
+    /// Shape of the tensor of a single image.
+    fn input_shape(&self) -> [usize; 4] {
+        let (width, height) = (self.config.image_size.width, self.config.image_size.height);
+        match self.config.channels {
+            Channels::CWH => [1, 3, width, height],
+            Channels::WHC => [1, width, height, 3],
+        }
     }
 
     pub fn extract_features(&self, image: RgbImage) -> Result<Vec<f64>, String> {
@@ -131,6 +178,86 @@ impl LoadedModel {
             .collect();
         Ok(features)
     }
+
+    /// Like `extract_features` for many images, which are run through the
+    /// model in a single call if it takes batches. An image which cannot be
+    /// transformed to the input shape of the model fails alone.
+    pub fn extract_features_batch(&self, images: Vec<RgbImage>) -> Vec<Result<Vec<f64>, String>> {
+        let batch_model = match &self.batch_model {
+            Some(batch_model) if images.len() > 1 => batch_model,
+            _ => {
+                return images
+                    .into_iter()
+                    .map(|image| self.extract_features(image))
+                    .collect()
+            }
+        };
+        println!("Transforming {} images", images.len());
+        let input_shape = self.input_shape();
+        let tensors: Vec<Result<Tensor, String>> = images
+            .iter()
+            .map(|image| {
+                let tensor = self.config.image_transformation.transform_image(image)?;
+                if tensor.shape() != input_shape {
+                    return Err(format!(
+                        "The image is transformed to shape {:?} instead of {:?}",
+                        tensor.shape(),
+                        input_shape
+                    ));
+                }
+                Ok(tensor)
+            })
+            .collect();
+        let valid: Vec<&Tensor> = tensors.iter().filter_map(|t| t.as_ref().ok()).collect();
+        if valid.is_empty() {
+            return tensors.into_iter().map(|t| t.map(|_| vec![])).collect();
+        }
+        println!("Running the model on {} images", valid.len());
+        match LoadedModel::run_batch(batch_model, &valid) {
+            Ok(features) => {
+                let mut features = features.into_iter();
+                tensors
+                    .into_iter()
+                    .map(|t| t.map(|_| features.next().unwrap_or_default()))
+                    .collect()
+            }
+            Err(e) => {
+                println!(
+                    "Cannot run the model on a batch, using one image at a time: {}",
+                    e
+                );
+                images
+                    .into_iter()
+                    .zip(tensors)
+                    .map(|(image, t)| t.and_then(|_| self.extract_features(image)))
+                    .collect()
+            }
+        }
+    }
+
+    /// The feature vector of every image in `tensors`, in order.
+    fn run_batch(model: &TractSimplePlan, tensors: &[&Tensor]) -> Result<Vec<Vec<f64>>, String> {
+        let batch = Tensor::stack_tensors(0, tensors).map_err(|e| e.to_string())?;
+        let result = model.run(tvec!(batch.into())).map_err(|e| e.to_string())?;
+        let features: Vec<f64> = result[0]
+            .to_array_view::<f32>()
+            .map_err(|e| e.to_string())?
+            .iter()
+            .map(|&v| v as f64)
+            .collect();
+        // the output starts with the batch dimension
+        if features.is_empty() || !features.len().is_multiple_of(tensors.len()) {
+            return Err(format!(
+                "Cannot split {} features between {} images",
+                features.len(),
+                tensors.len()
+            ));
+        }
+        Ok(features
+            .chunks(features.len() / tensors.len())
+            .map(|features| features.to_vec())
+            .collect())
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, JsonSchema)]
@@ -146,6 +273,8 @@ pub enum ModelArchitecture {
 mod tests {
     use super::*;
     use crate::image_transform::functions::read_rgb_image;
+    use tract_onnx::tract_hir::ops::expandable::expand;
+    use tract_onnx::tract_hir::ops::nn::{Reduce, Reducer};
 
     #[test]
     fn test_feature_extraction() {
@@ -153,5 +282,49 @@ mod tests {
         let image = read_rgb_image("images/cat.jpeg");
         let features = model.extract_features(image).unwrap();
         assert_eq!(features.len(), 1280);
+        assert_eq!(model.dimension(), Some(1280));
+    }
+
+    #[test]
+    fn test_feature_extraction_batch() {
+        // sums every channel of the image
+        let mut model = InferenceModel::default();
+        let input = model.add_source("input", InferenceFact::default()).unwrap();
+        let sum = Reduce::new(Some(vec![2, 3]), false, Reducer::Sum);
+        let output = model.wire_node("sum", expand(sum), &[input]).unwrap();
+        model.set_output_outlets(&output).unwrap();
+        let config = ModelConfig {
+            model_name: "ChannelSum".into(),
+            model_url: "".into(),
+            model_type: ModelType::ONNX,
+            image_transformation: TransformationPipeline { steps: vec![] },
+            image_size: ImageSize {
+                width: 4,
+                height: 4,
+            },
+            layer_name: None,
+            channels: Channels::CWH,
+        };
+        let model = LoadedModel::new_from_inference_model(config, model);
+        assert!(model.batch_model.is_some());
+        assert_eq!(model.dimension(), Some(3));
+
+        let image =
+            |size: u32, value: u8| RgbImage::from_pixel(size, size, image::Rgb([value, 1, 2]));
+        let images = vec![image(4, 10), image(5, 20), image(4, 30)];
+        let features = model.extract_features_batch(images.clone());
+        assert_eq!(features.len(), 3);
+        assert_eq!(features[0], Ok(vec![160.0, 16.0, 32.0]));
+        // the image is too large for the model
+        assert!(features[1].is_err());
+        assert_eq!(features[2], Ok(vec![480.0, 16.0, 32.0]));
+        for i in [0, 2] {
+            assert_eq!(
+                features[i],
+                model.extract_features(images[i].clone()),
+                "image {}",
+                i
+            );
+        }
     }
 }
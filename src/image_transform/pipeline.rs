@@ -41,11 +41,11 @@ impl ImageTransformResult {
                 vec![width as usize, height as usize]
             }
             ImageTransformResult::Array4(array) => {
-                let shape: Vec<usize> = array.shape().iter().map(|v| *v as usize).collect();
+                let shape: Vec<usize> = array.shape().to_vec();
                 shape
             }
             ImageTransformResult::Tensor(tensor) => {
-                let shape: Vec<usize> = tensor.shape().iter().map(|v| *v as usize).collect();
+                let shape: Vec<usize> = tensor.shape().to_vec();
                 shape
             }
         }
@@ -176,8 +176,8 @@ impl GenericTransform for CenterCrop {
                 let mut image_cropped = image;
                 let image_cropped_new = crop(
                     &mut image_cropped,
-                    top as u32,
-                    left as u32,
+                    top,
+                    left,
                     self.crop_size.width as u32,
                     self.crop_size.height as u32,
                 );
@@ -376,6 +376,6 @@ mod tests {
             .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
 
         // this is classified as a lynx which is close enough I guess
-        assert!(vec![283, 287].contains(&best.unwrap().1));
+        assert!([283, 287].contains(&best.unwrap().1));
     }
 }
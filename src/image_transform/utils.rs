@@ -46,7 +46,8 @@ pub fn save_file_get(url: &str, path: &str) -> Result<(), String> {
         fs::create_dir("models").map_err(|e| e.to_string())?;
     }
     let mut out = fs::File::create(path).map_err(|e| e.to_string())?;
-    out.write_all(&response.bytes().expect("Failed to convert to bytes"));
+    out.write_all(&response.bytes().expect("Failed to convert to bytes"))
+        .map_err(|e| e.to_string())?;
 
     Ok(())
 }
@@ -54,9 +55,8 @@ pub fn save_file_get(url: &str, path: &str) -> Result<(), String> {
 pub fn read_bytes_url(url: &str) -> reqwest::Result<Bytes> {
     let client = reqwest::blocking::Client::builder()
         .referer(false)
-        .build()
-        .map_err(|e| e)?;
-    let response = client.get(url).send().map_err(|e| e)?;
+        .build()?;
+    let response = client.get(url).send()?;
     response.bytes()
 }
 
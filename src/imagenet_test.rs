@@ -1,17 +1,10 @@
-use crate::image_transform::architectures::load_model_config;
-use crate::image_transform::functions::read_rgb_image;
-use crate::image_transform::models::{LoadedModel, ModelArchitecture};
-use crate::index::events::{AddImage, ImageSource, UpsertCollection};
-use crate::state::app::{EmbeddingApp, GenericModelConfig};
 use glob::glob;
-use reqwest::Url;
 use std::str::FromStr;
 use std::time::Instant;
 use tract_onnx::prelude::*;
-
-mod image_transform;
-mod index;
-mod state;
+use visual_search::image_transform::architectures::load_model_config;
+use visual_search::image_transform::functions::read_rgb_image;
+use visual_search::image_transform::models::{LoadedModel, ModelArchitecture};
 
 fn main() -> Result<(), String> {
     let mut config = load_model_config(ModelArchitecture::EfficientNetLite4);
@@ -53,7 +46,7 @@ fn main() -> Result<(), String> {
             .to_str()
             .unwrap()
             .split('/')
-            .last()
+            .next_back()
             .unwrap()
             .split('.')
             .next()
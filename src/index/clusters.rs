@@ -0,0 +1,122 @@
+use crate::index::db::{CollectionIndex, DistanceMetric, SearchIndex};
+use crate::index::kmeans::{assign, kmeans, normalize};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ClusterMember {
+    pub id: String,
+    // distance to the centroid of the cluster
+    pub distance: f64,
+}
+
+/// A group of visually similar images, the members are sorted by their
+/// distance to the centroid so the first ones are the most central.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Cluster {
+    pub centroid: Vec<f64>,
+    pub members: Vec<ClusterMember>,
+}
+
+/// Runs k-means (k-means++ initialization) over the live vectors of the
+/// index. For cosine distance the vectors are normalized first. Empty
+/// clusters are dropped, the others are sorted by size, largest first.
+pub fn cluster_index(
+    index: &CollectionIndex,
+    metric: DistanceMetric,
+    k: usize,
+    n_iterations: usize,
+    seed: u64,
+) -> Vec<Cluster> {
+    let mut ids = index.ids();
+    ids.sort_unstable();
+    // the image may have been removed since listing the ids
+    let (ids, vectors): (Vec<String>, Vec<Vec<f64>>) = ids
+        .into_iter()
+        .filter_map(|id| {
+            let v = index.vector(&id)?;
+            let v = match metric {
+                DistanceMetric::Cosine => normalize(v),
+                _ => v,
+            };
+            Some((id, v))
+        })
+        .unzip();
+
+    let centroids = kmeans(&vectors, k, n_iterations, seed);
+    let assignments = assign(&centroids, &vectors);
+    let mut clusters: Vec<Cluster> = centroids
+        .into_iter()
+        .map(|centroid| Cluster {
+            centroid,
+            members: vec![],
+        })
+        .collect();
+    for ((id, v), cluster) in ids.into_iter().zip(&vectors).zip(assignments) {
+        let distance = metric.float_distance(v, &clusters[cluster].centroid);
+        clusters[cluster]
+            .members
+            .push(ClusterMember { id, distance });
+    }
+
+    clusters.retain(|cluster| !cluster.members.is_empty());
+    for cluster in clusters.iter_mut() {
+        cluster
+            .members
+            .sort_by(|a, b| a.distance.total_cmp(&b.distance).then(a.id.cmp(&b.id)));
+    }
+    clusters.sort_by_key(|cluster| Reverse(cluster.members.len()));
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::db::{IndexBackend, IndexConfig};
+
+    #[test]
+    fn test_cluster_index() {
+        let index = CollectionIndex::new(&IndexConfig {
+            backend: IndexBackend::Flat,
+            ..Default::default()
+        });
+        // three blobs of 20, 10 and 5 images, the center of every blob is an image
+        let centers = [(0.0, 0.0, 20), (10.0, 10.0, 10), (-10.0, 10.0, 5)];
+        for (c, &(x, y, n)) in centers.iter().enumerate() {
+            index.insert(vec![x, y], format!("{}-center", c));
+            for i in 1..n {
+                let angle = 2.0 * std::f64::consts::PI * i as f64 / (n - 1) as f64;
+                index.insert(
+                    vec![x + angle.cos(), y + angle.sin()],
+                    format!("{}-{}", c, i),
+                );
+            }
+        }
+        index.remove("0-1".into());
+
+        let clusters = cluster_index(&index, DistanceMetric::Euclidean, 3, 20, 42);
+        let sizes: Vec<usize> = clusters.iter().map(|c| c.members.len()).collect();
+        assert_eq!(sizes, [19, 10, 5]);
+        for (c, cluster) in clusters.iter().enumerate() {
+            assert!(cluster
+                .members
+                .iter()
+                .all(|m| m.id.starts_with(&c.to_string())));
+            assert_eq!(cluster.members[0].id, format!("{}-center", c));
+            assert!(cluster.members[0].distance < cluster.members[1].distance);
+        }
+
+        assert_eq!(
+            cluster_index(&index, DistanceMetric::Euclidean, 3, 20, 42),
+            clusters
+        );
+        assert_eq!(
+            cluster_index(&index, DistanceMetric::Euclidean, 100, 20, 42)
+                .iter()
+                .map(|c| c.members.len())
+                .sum::<usize>(),
+            34
+        );
+    }
+}
@@ -1,25 +1,284 @@
-use core::mem;
-use std::collections::HashSet;
-use std::sync::{Arc, RwLock};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
 
-use hnsw::{Hnsw, Searcher};
+use enum_dispatch::enum_dispatch;
+use hnsw::{Hnsw, Params, Searcher};
 use rand_pcg::Pcg64;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use space::{Metric, Neighbor};
 
+use crate::index::flat::FlatIndex;
+use crate::index::ivf_pq::IvfPqIndex;
+use crate::index::pca::PcaConfig;
+use crate::index::quantization::{Quantizer, StoredVector, VectorPrecision, INT8_TRAINING_SIZE};
+use crate::index::rerank::FullVectors;
+
 const MAX_REMOVED_BEFORE_REBUILD: usize = 100;
+pub const DEFAULT_EF: usize = 64;
+// upper bound on the results of a range search
+pub const MAX_RANGE_RESULTS: usize = 10_000;
+// candidates fetched per requested result in the first round of a filtered search
+const FILTER_OVERFETCH: usize = 4;
 
-#[derive(Clone)]
-pub struct Euclidean;
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum DistanceMetric {
+    #[default]
+    Euclidean,
+    // 1 - cosine similarity
+    Cosine,
+    // negative dot product, so that larger products are closer
+    InnerProduct,
+    // number of values differing by more than 0.5, for vectors of bits. They
+    // are stored packed, values above 0.5 are set
+    Hamming,
+}
+
+impl DistanceMetric {
+    pub fn float_distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        self.pairs_distance(a.iter().copied().zip(b.iter().copied()))
+    }
+
+    /// Distance between two vectors given as pairs of values of the same
+    /// dimension. Computed in a single pass so it works on vectors which are
+    /// decoded on the fly.
+    pub fn pairs_distance<I: Iterator<Item = (f64, f64)>>(&self, pairs: I) -> f64 {
+        match self {
+            DistanceMetric::Euclidean => pairs.map(|(a, b)| (a - b).powi(2)).sum::<f64>().sqrt(),
+            DistanceMetric::Cosine => {
+                let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+                for (a, b) in pairs {
+                    dot += a * b;
+                    norm_a += a * a;
+                    norm_b += b * b;
+                }
+                if norm_a == 0.0 || norm_b == 0.0 {
+                    1.0
+                } else {
+                    (1.0 - dot / (norm_a.sqrt() * norm_b.sqrt())).max(0.0)
+                }
+            }
+            DistanceMetric::InnerProduct => -pairs.map(|(a, b)| a * b).sum::<f64>(),
+            DistanceMetric::Hamming => pairs.filter(|(a, b)| (a - b).abs() > 0.5).count() as f64,
+        }
+    }
+
+    /// Turns a distance into a score in [0, 1] where 1 means identical.
+    pub fn similarity(&self, distance: f64) -> f64 {
+        match self {
+            DistanceMetric::Euclidean | DistanceMetric::Hamming => 1.0 / (1.0 + distance),
+            // cosine distance is in [0, 2]
+            DistanceMetric::Cosine => (1.0 - distance / 2.0).clamp(0.0, 1.0),
+            // logistic function of the dot product
+            DistanceMetric::InnerProduct => 1.0 / (1.0 + distance.exp()),
+        }
+    }
+}
+
+/// Maps a float to an integer with the same ordering (including negative
+/// values) because HNSW needs an unsigned integer distance.
+pub fn to_ordered_bits(value: f64) -> u64 {
+    let bits = value.to_bits();
+    if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | (1 << 63)
+    }
+}
+
+fn from_ordered_bits(bits: u64) -> f64 {
+    if bits >> 63 == 1 {
+        f64::from_bits(bits & !(1 << 63))
+    } else {
+        f64::from_bits(!bits)
+    }
+}
 
-impl Metric<Vec<f64>> for Euclidean {
-    type Unit = u64;
-    fn distance(&self, a: &Vec<f64>, b: &Vec<f64>) -> u64 {
-        a.iter()
-            .zip(b.iter())
-            .map(|(&a1, &b1)| (a1 - b1).powi(2))
-            .sum::<f64>()
-            .sqrt()
-            .to_bits() as u64
+/// Number of neighbors per node in the HNSW graph. M0 (neighbors in the
+/// bottom layer) is always 2 * M. More neighbors give better recall at the
+/// cost of memory and insertion time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum HnswConnections {
+    M6,
+    #[default]
+    M12,
+    M24,
+    M48,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct HnswConfig {
+    #[serde(default)]
+    pub connections: HnswConnections,
+    // candidate pool size used when inserting
+    #[serde(default = "default_ef_construction")]
+    pub ef_construction: usize,
+}
+
+fn default_ef_construction() -> usize {
+    400
+}
+
+impl Default for HnswConfig {
+    fn default() -> Self {
+        HnswConfig {
+            connections: HnswConnections::default(),
+            ef_construction: default_ef_construction(),
+        }
+    }
+}
+
+/// Data structure used to answer searches. `Flat` compares the query with
+/// every vector, which is exact but linear in the size of the collection.
+/// `IvfPq` compresses vectors to a few bytes for very large collections.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum IndexBackend {
+    #[default]
+    Hnsw,
+    Flat,
+    IvfPq,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct IvfPqConfig {
+    // number of coarse k-means partitions
+    #[serde(default = "default_n_lists")]
+    pub n_lists: usize,
+    // every vector is stored as one byte per subvector
+    #[serde(default = "default_n_subvectors")]
+    pub n_subvectors: usize,
+    // number of partitions scanned per search
+    #[serde(default = "default_n_probe")]
+    pub n_probe: usize,
+    // number of vectors to collect before training, searches are exact until then
+    #[serde(default = "default_training_size")]
+    pub training_size: usize,
+    // keep the full vectors to re-rank the top `ef` candidates by exact distance
+    #[serde(default)]
+    pub rerank: bool,
+}
+
+fn default_n_lists() -> usize {
+    256
+}
+
+fn default_n_subvectors() -> usize {
+    16
+}
+
+fn default_n_probe() -> usize {
+    16
+}
+
+fn default_training_size() -> usize {
+    20_000
+}
+
+impl Default for IvfPqConfig {
+    fn default() -> Self {
+        IvfPqConfig {
+            n_lists: default_n_lists(),
+            n_subvectors: default_n_subvectors(),
+            n_probe: default_n_probe(),
+            training_size: default_training_size(),
+            rerank: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct IndexConfig {
+    #[serde(default)]
+    pub backend: IndexBackend,
+    // Euclidean by default, Hamming for perceptual hashes
+    #[serde(default)]
+    pub metric: Option<DistanceMetric>,
+    #[serde(default)]
+    pub precision: VectorPrecision,
+    #[serde(default)]
+    pub hnsw: HnswConfig,
+    #[serde(default)]
+    pub ivf_pq: IvfPqConfig,
+    // reduce the dimension of vectors before indexing them
+    #[serde(default)]
+    pub pca: Option<PcaConfig>,
+    // also keep the vectors in double precision so searches can re-rank
+    // their candidates by exact distance
+    #[serde(default)]
+    pub full_vectors: bool,
+}
+
+impl IndexConfig {
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric.unwrap_or_default()
+    }
+}
+
+// M and M0 are const generics so every supported pair is a separate type
+#[derive(Clone, Serialize, Deserialize)]
+pub enum HnswGraph {
+    M6(Hnsw<Quantizer, StoredVector, Pcg64, 6, 12>),
+    M12(Hnsw<Quantizer, StoredVector, Pcg64, 12, 24>),
+    M24(Hnsw<Quantizer, StoredVector, Pcg64, 24, 48>),
+    M48(Hnsw<Quantizer, StoredVector, Pcg64, 48, 96>),
+}
+
+macro_rules! with_graph {
+    ($graph:expr, $hnsw:ident => $body:expr) => {
+        match $graph {
+            HnswGraph::M6($hnsw) => $body,
+            HnswGraph::M12($hnsw) => $body,
+            HnswGraph::M24($hnsw) => $body,
+            HnswGraph::M48($hnsw) => $body,
+        }
+    };
+}
+
+impl HnswGraph {
+    pub fn new(quantizer: Quantizer, config: &HnswConfig) -> Self {
+        let params = Params::new().ef_construction(config.ef_construction);
+        match config.connections {
+            HnswConnections::M6 => HnswGraph::M6(Hnsw::new_params(quantizer, params)),
+            HnswConnections::M12 => HnswGraph::M12(Hnsw::new_params(quantizer, params)),
+            HnswConnections::M24 => HnswGraph::M24(Hnsw::new_params(quantizer, params)),
+            HnswConnections::M48 => HnswGraph::M48(Hnsw::new_params(quantizer, params)),
+        }
+    }
+
+    pub fn insert(&mut self, v: StoredVector, searcher: &mut Searcher<u64>) -> usize {
+        with_graph!(self, hnsw => hnsw.insert(v, searcher))
+    }
+
+    pub fn feature(&self, index: usize) -> &StoredVector {
+        with_graph!(self, hnsw => hnsw.feature(index))
+    }
+
+    pub fn len(&self) -> usize {
+        with_graph!(self, hnsw => hnsw.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn nearest<'a>(
+        &self,
+        q: &StoredVector,
+        ef: usize,
+        searcher: &mut Searcher<u64>,
+        dest: &'a mut [Neighbor<u64>],
+    ) -> &'a mut [Neighbor<u64>] {
+        with_graph!(self, hnsw => hnsw.nearest(q, ef, searcher, dest))
+    }
+
+    /// Nodes linked to node `index` in the bottom layer of the graph.
+    pub fn neighbors(&self, index: usize) -> Vec<usize> {
+        with_graph!(self, hnsw => hnsw.zero_neighbors(index).collect())
     }
 }
 
@@ -27,109 +286,562 @@ impl Metric<Vec<f64>> for Euclidean {
 pub struct AnnNeighbor {
     pub id: String,
     pub index: usize,
-    pub distance: u32,
+    pub distance: f64,
+    pub similarity: f64,
 }
 
+pub type IndexedVectors = Vec<(String, StoredVector)>;
+
+#[enum_dispatch(CollectionIndex)]
+pub trait SearchIndex {
+    fn insert(&self, v: Vec<f64>, id: String);
+    /// Returns up to `k` nearest neighbors which were not removed. `ef` is the
+    /// size of the candidate pool for approximate indexes, larger values give
+    /// better recall but slower searches.
+    fn search(&self, v: &[f64], k: usize, ef: usize) -> Vec<AnnNeighbor>;
+    /// Like `search` but only returns neighbors whose id is accepted by
+    /// `accept`. By default more and more candidates are fetched until `k` of
+    /// them are accepted or the index has no more, the number of candidates is
+    /// estimated from the share of accepted ones so far.
+    fn search_filtered(
+        &self,
+        v: &[f64],
+        k: usize,
+        ef: usize,
+        accept: &(dyn Fn(&str) -> bool + Sync),
+    ) -> Vec<AnnNeighbor> {
+        let mut n_candidates = k.max(1) * FILTER_OVERFETCH;
+        loop {
+            let candidates = self.search(v, n_candidates, ef.max(n_candidates));
+            let n_found = candidates.len();
+            let accepted: Vec<AnnNeighbor> =
+                candidates.into_iter().filter(|n| accept(&n.id)).collect();
+            if accepted.len() >= k || n_found < n_candidates {
+                return accepted.into_iter().take(k).collect();
+            }
+            let share = accepted.len().max(1) as f64 / n_found as f64;
+            n_candidates = (n_candidates * 2).max((1.5 * k as f64 / share) as usize);
+        }
+    }
+    /// All accepted neighbors within `radius` of `v`, closest first, at most
+    /// `max_results` of them. Indexes look for neighbors by their distance,
+    /// not by fetching the top `max_results` and dropping the farther ones.
+    fn search_range(
+        &self,
+        v: &[f64],
+        radius: f64,
+        max_results: usize,
+        ef: usize,
+        accept: &(dyn Fn(&str) -> bool + Sync),
+    ) -> Vec<AnnNeighbor>;
+    /// Returns false if there is no image with this id.
+    fn remove(&self, id: String) -> bool;
+    fn contains(&self, id: &str) -> bool;
+    /// Ids of all images which were not removed, in no particular order.
+    fn ids(&self) -> Vec<String>;
+    /// The vector stored for `id`, in storage precision.
+    fn vector(&self, id: &str) -> Option<Vec<f64>>;
+    /// The vector inserted for `id`, `None` unless the config sets
+    /// `full_vectors`.
+    fn full_vector(&self, id: &str) -> Option<Vec<f64>>;
+}
+
+#[enum_dispatch]
+#[derive(Clone)]
+pub enum CollectionIndex {
+    Hnsw(VectorIndex),
+    Flat(FlatIndex),
+    IvfPq(IvfPqIndex),
+}
+
+impl CollectionIndex {
+    pub fn new(config: &IndexConfig) -> Self {
+        match config.backend {
+            IndexBackend::Hnsw => VectorIndex::new(config).into(),
+            IndexBackend::Flat => FlatIndex::new(config).into(),
+            IndexBackend::IvfPq => IvfPqIndex::new(config).into(),
+        }
+    }
+
+    pub fn save<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        match self {
+            CollectionIndex::Hnsw(index) => index.save(writer),
+            CollectionIndex::Flat(index) => index.save(writer),
+            CollectionIndex::IvfPq(index) => index.save(writer),
+        }
+    }
+
+    /// Loads an index written by `save`, `config` tells which backend wrote it.
+    pub fn load<R: Read>(config: &IndexConfig, reader: R) -> Result<Self, Box<dyn Error>> {
+        Ok(match config.backend {
+            IndexBackend::Hnsw => VectorIndex::load(reader)?.into(),
+            IndexBackend::Flat => FlatIndex::load(reader)?.into(),
+            IndexBackend::IvfPq => IvfPqIndex::load(reader)?.into(),
+        })
+    }
+
+    /// Number of images in the index.
+    pub fn len(&self) -> usize {
+        match self {
+            CollectionIndex::Hnsw(index) => index.positions.read().unwrap().len(),
+            CollectionIndex::Flat(index) => index.vectors.read().unwrap().len(),
+            CollectionIndex::IvfPq(index) => index.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Approximate nearest neighbor index. The vectors are only stored in the
+/// HNSW graph, `ids[i]` is the id of node `i`. Locks are always taken in the
+/// order of the fields.
 #[derive(Clone)]
 pub struct VectorIndex {
-    pub searcher: Arc<RwLock<Searcher<u64>>>,
-    pub hnsw: Arc<RwLock<Hnsw<Euclidean, Vec<f64>, Pcg64, 12, 24>>>,
-    pub vectors: Arc<RwLock<Vec<(String, Vec<f64>)>>>,
-    pub removed: Arc<RwLock<HashSet<String>>>,
+    pub config: IndexConfig,
+    pub hnsw: Arc<RwLock<HnswGraph>>,
+    pub quantizer: Arc<RwLock<Quantizer>>,
+    pub ids: Arc<RwLock<Vec<String>>>,
+    // node index of every id which is not removed
+    pub positions: Arc<RwLock<HashMap<String, usize>>>,
+    // removed (or replaced) node indexes, dropped by the next rebuild
+    pub removed: Arc<RwLock<HashSet<usize>>>,
+    full_vectors: FullVectors,
+    // changes made while a rebuild is running, None if there is no rebuild
+    rebuild_changes: Arc<Mutex<Option<Vec<IndexChange>>>>,
+    rebuild_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+#[derive(Clone)]
+enum IndexChange {
+    Insert(String, Vec<f64>),
+    Remove(String),
+}
+
+fn decode_all(hnsw: &HnswGraph, quantizer: &Quantizer) -> Vec<Vec<f64>> {
+    (0..hnsw.len())
+        .map(|index| quantizer.decode(hnsw.feature(index)))
+        .collect()
+}
+
+/// Node index of every id which is not removed.
+pub fn live_positions(ids: &[String], removed: &HashSet<usize>) -> HashMap<String, usize> {
+    ids.iter()
+        .enumerate()
+        .filter(|(index, _)| !removed.contains(index))
+        .map(|(index, id)| (id.clone(), index))
+        .collect()
 }
 
 impl VectorIndex {
-    pub fn new() -> Self {
+    pub fn new(config: &IndexConfig) -> Self {
+        let quantizer = Quantizer::new(config.metric(), config.precision);
         VectorIndex {
-            searcher: Arc::new(RwLock::new(Searcher::default())),
-            hnsw: Arc::new(RwLock::new(Hnsw::new(Euclidean))),
-            vectors: Arc::new(RwLock::new(Vec::new())),
+            config: config.clone(),
+            hnsw: Arc::new(RwLock::new(HnswGraph::new(quantizer.clone(), &config.hnsw))),
+            quantizer: Arc::new(RwLock::new(quantizer)),
+            ids: Arc::new(RwLock::new(Vec::new())),
+            positions: Arc::new(Default::default()),
             removed: Arc::new(Default::default()),
+            full_vectors: FullVectors::new(config),
+            rebuild_changes: Arc::new(Mutex::new(None)),
+            rebuild_thread: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Writes the graph, the ids and the removed nodes so the index can be
+    /// restored with `VectorIndex::load` without re-inserting anything.
+    pub fn save<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        let hnsw = self.hnsw.read().map_err(|_| "RwLock Error")?;
+        let quantizer = self.quantizer.read().map_err(|_| "RwLock Error")?;
+        let ids = self.ids.read().map_err(|_| "RwLock Error")?;
+        let removed = self.removed.read().map_err(|_| "RwLock Error")?;
+        bincode::serialize_into(
+            writer,
+            &(
+                &self.config,
+                &*hnsw,
+                &*quantizer,
+                &*ids,
+                &*removed,
+                &self.full_vectors,
+            ),
+        )?;
+        Ok(())
+    }
+
+    pub fn load<R: Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        let (config, hnsw, quantizer, ids, removed, full_vectors): (
+            IndexConfig,
+            HnswGraph,
+            Quantizer,
+            Vec<String>,
+            HashSet<usize>,
+            FullVectors,
+        ) = bincode::deserialize_from(reader)?;
+        let positions = live_positions(&ids, &removed);
+        Ok(VectorIndex {
+            config,
+            hnsw: Arc::new(RwLock::new(hnsw)),
+            quantizer: Arc::new(RwLock::new(quantizer)),
+            ids: Arc::new(RwLock::new(ids)),
+            positions: Arc::new(RwLock::new(positions)),
+            removed: Arc::new(RwLock::new(removed)),
+            full_vectors,
+            rebuild_changes: Arc::new(Mutex::new(None)),
+            rebuild_thread: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    fn exact_neighbors(
+        &self,
+        query: &StoredVector,
+        hnsw: &HnswGraph,
+        quantizer: &Quantizer,
+        n: usize,
+    ) -> Vec<Neighbor<u64>> {
+        let mut neighbors: Vec<_> = (0..hnsw.len())
+            .map(|index| Neighbor {
+                index,
+                distance: quantizer.distance(query, hnsw.feature(index)),
+            })
+            .collect();
+        neighbors.sort_by_key(|n| n.distance);
+        neighbors.truncate(n);
+        neighbors
+    }
+
+    /// Learns the int8 scales from the vectors stored so far and rebuilds the
+    /// graph with the quantized vectors.
+    fn train(&self, hnsw: &mut HnswGraph, quantizer: &mut Quantizer) {
+        let vectors = decode_all(hnsw, quantizer);
+        quantizer.train(&vectors);
+        *hnsw = self.encode_graph(&vectors, quantizer);
+    }
+
+    /// A new graph of `vectors` stored with `quantizer`, in the same order so
+    /// node indexes do not change.
+    fn encode_graph(&self, vectors: &[Vec<f64>], quantizer: &Quantizer) -> HnswGraph {
+        let mut hnsw = HnswGraph::new(quantizer.clone(), &self.config.hnsw);
+        let mut searcher = Searcher::default();
+        for v in vectors {
+            hnsw.insert(quantizer.encode(v), &mut searcher);
+        }
+        hnsw
+    }
+
+    /// Drops removed nodes by building a new graph. Searches, inserts and
+    /// removals keep working on the current graph in the meantime, the changes
+    /// made during the rebuild are replayed on the new graph before it replaces
+    /// the current one. Does nothing if a rebuild is already running.
+    pub fn rebuild(&self) {
+        if let Some((quantizer, items)) = self.begin_rebuild() {
+            let (hnsw, ids) = self.build_graph(quantizer.clone(), items);
+            self.finish_rebuild(quantizer, hnsw, ids);
         }
     }
 
-    pub fn insert(&self, v: Vec<f64>, id: String) {
+    /// Runs `rebuild` on a separate thread unless one is already running.
+    pub fn rebuild_in_background(&self) {
+        let mut rebuild_thread = self.rebuild_thread.lock().unwrap();
+        if rebuild_thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+        {
+            return;
+        }
+        let index = self.clone();
+        *rebuild_thread = Some(thread::spawn(move || index.rebuild()));
+    }
+
+    pub fn wait_for_rebuild(&self) {
+        let rebuild_thread = self.rebuild_thread.lock().unwrap().take();
+        if let Some(thread) = rebuild_thread {
+            thread.join().unwrap();
+        }
+    }
+
+    /// Copies the vectors which are not removed and starts recording changes.
+    fn begin_rebuild(&self) -> Option<(Quantizer, Vec<(String, StoredVector)>)> {
+        let hnsw = self.hnsw.read().unwrap();
+        let quantizer = self.quantizer.read().unwrap();
+        let ids = self.ids.read().unwrap();
+        let removed = self.removed.read().unwrap();
+        let mut changes = self.rebuild_changes.lock().unwrap();
+        if changes.is_some() {
+            return None;
+        }
+        *changes = Some(Vec::new());
+        let items = ids
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !removed.contains(index))
+            .map(|(index, id)| (id.clone(), hnsw.feature(index).clone()))
+            .collect();
+        Some((quantizer.clone(), items))
+    }
+
+    fn build_graph(
+        &self,
+        quantizer: Quantizer,
+        items: Vec<(String, StoredVector)>,
+    ) -> (HnswGraph, Vec<String>) {
+        let mut hnsw = HnswGraph::new(quantizer, &self.config.hnsw);
+        let mut searcher = Searcher::default();
+        let mut ids = Vec::with_capacity(items.len());
+        for (id, v) in items {
+            hnsw.insert(v, &mut searcher);
+            ids.push(id);
+        }
+        (hnsw, ids)
+    }
+
+    /// Replays the changes made since `begin_rebuild` on the new graph and
+    /// swaps it in while holding all locks.
+    fn finish_rebuild(
+        &self,
+        quantizer: Quantizer,
+        mut new_hnsw: HnswGraph,
+        mut new_ids: Vec<String>,
+    ) {
         let mut hnsw = self.hnsw.write().unwrap();
-        let mut searcher = self.searcher.write().unwrap();
-        let mut vectors = self.vectors.write().unwrap();
-        vectors.push((id, v.clone()));
-        hnsw.insert(v, &mut searcher);
-    }
-
-    pub fn search(&self, v: &[f64]) -> Vec<AnnNeighbor> {
-        let mut neighbors = [Neighbor {
-            index: !0,
-            distance: !0,
-        }; 8];
-        let mut searcher = self.searcher.write().unwrap();
-        let hnsw = self.hnsw.write().unwrap();
+        let live_quantizer = self.quantizer.read().unwrap();
+        let mut ids = self.ids.write().unwrap();
+        let mut positions = self.positions.write().unwrap();
+        let mut removed = self.removed.write().unwrap();
+        let mut changes = self.rebuild_changes.lock().unwrap();
+
+        // int8 scales learned while the graph was built are kept, the new
+        // graph is converted to them
+        if quantizer.needs_training() && !live_quantizer.needs_training() {
+            new_hnsw = self.encode_graph(&decode_all(&new_hnsw, &quantizer), &live_quantizer);
+        }
+        let mut searcher = Searcher::default();
+        let mut new_removed = HashSet::new();
+        let mut new_positions = live_positions(&new_ids, &new_removed);
+        for change in changes.take().unwrap_or_default() {
+            match change {
+                IndexChange::Insert(id, v) => {
+                    if let Some(old) = new_positions.insert(id.clone(), new_ids.len()) {
+                        new_removed.insert(old);
+                    }
+                    new_ids.push(id);
+                    new_hnsw.insert(live_quantizer.encode(&v), &mut searcher);
+                }
+                IndexChange::Remove(id) => {
+                    if let Some(old) = new_positions.remove(&id) {
+                        new_removed.insert(old);
+                    }
+                }
+            }
+        }
+
+        *hnsw = new_hnsw;
+        *ids = new_ids;
+        *positions = new_positions;
+        *removed = new_removed;
+    }
+
+    fn record_change(&self, change: IndexChange) {
+        if let Some(changes) = self.rebuild_changes.lock().unwrap().as_mut() {
+            changes.push(change);
+        }
+    }
+}
+
+impl SearchIndex for VectorIndex {
+    /// Adding an id which is already in the index replaces its vector: the
+    /// old node is marked as removed and a new one is inserted.
+    fn insert(&self, v: Vec<f64>, id: String) {
+        let mut hnsw = self.hnsw.write().unwrap();
+        let mut quantizer = self.quantizer.write().unwrap();
+        let mut ids = self.ids.write().unwrap();
+        let mut positions = self.positions.write().unwrap();
+        let mut removed = self.removed.write().unwrap();
+        if let Some(old) = positions.insert(id.clone(), ids.len()) {
+            removed.insert(old);
+        }
+        self.full_vectors.insert(&id, &v);
+        self.record_change(IndexChange::Insert(id.clone(), v.clone()));
+        ids.push(id);
+        hnsw.insert(quantizer.encode(&v), &mut Searcher::default());
+        if quantizer.needs_training() && ids.len() >= INT8_TRAINING_SIZE {
+            self.train(&mut hnsw, &mut quantizer);
+        }
+        // replaced vectors count as removed
+        let needs_rebuild = removed.len() > MAX_REMOVED_BEFORE_REBUILD;
+        drop(removed);
+        drop(positions);
+        drop(ids);
+        drop(quantizer);
+        drop(hnsw);
+        if needs_rebuild {
+            self.rebuild_in_background();
+        }
+    }
+
+    fn search(&self, v: &[f64], k: usize, ef: usize) -> Vec<AnnNeighbor> {
+        let hnsw = self.hnsw.read().unwrap();
+        let quantizer = self.quantizer.read().unwrap();
+        let ids = self.ids.read().unwrap();
         let removed = self.removed.read().unwrap();
-        let vectors = self.vectors.read().unwrap();
-        hnsw.nearest(&v.to_vec(), 8, &mut searcher, &mut neighbors);
-        println!("Neighbors {:?}", neighbors);
+        // every search has its own state so searches run concurrently
+        let mut searcher = Searcher::default();
+
+        // fetch enough candidates to still have k after dropping removed items
+        let n_candidates = (k + removed.len()).min(ids.len());
+        let mut neighbors = vec![
+            Neighbor {
+                index: !0,
+                distance: !0,
+            };
+            n_candidates
+        ];
+        let query = quantizer.encode_query(v);
+        let found = hnsw
+            .nearest(&query, ef.max(n_candidates), &mut searcher, &mut neighbors)
+            .len();
+        // fewer neighbors than items are found when some items are not
+        // reachable in the graph, an exact scan finds them all
+        if found < n_candidates {
+            neighbors = self.exact_neighbors(&query, &hnsw, &quantizer, n_candidates);
+        }
         neighbors
             .iter()
-            .filter(|e| {
-                let id = e.index;
-                let id_val = vectors[id].0.clone();
-                !removed.contains(&id_val)
-            })
-            .cloned()
+            .filter(|n| !removed.contains(&n.index))
+            .take(k)
             .map(|n| AnnNeighbor {
-                id: vectors[n.index].0.clone(),
+                id: ids[n.index].clone(),
                 index: n.index,
-                distance: n.distance as u32,
+                distance: from_ordered_bits(n.distance),
+                similarity: self
+                    .config
+                    .metric()
+                    .similarity(from_ordered_bits(n.distance)),
             })
             .collect()
     }
 
-    pub fn remove(&self, id: String) {
-        let mut removed = self.removed.write().unwrap();
-        removed.insert(id);
-        if removed.len() > MAX_REMOVED_BEFORE_REBUILD {
-            self.rebuild();
+    /// Starts from the `ef` nearest nodes and walks the graph from the
+    /// closest unvisited node, until that node is outside the radius or
+    /// `max_results` are found. Removed and rejected nodes are walked through
+    /// but not returned.
+    fn search_range(
+        &self,
+        v: &[f64],
+        radius: f64,
+        max_results: usize,
+        ef: usize,
+        accept: &(dyn Fn(&str) -> bool + Sync),
+    ) -> Vec<AnnNeighbor> {
+        let hnsw = self.hnsw.read().unwrap();
+        let quantizer = self.quantizer.read().unwrap();
+        let ids = self.ids.read().unwrap();
+        let removed = self.removed.read().unwrap();
+        let mut searcher = Searcher::default();
+
+        let query = quantizer.encode_query(v);
+        let n_start = ef.max(1).min(hnsw.len());
+        let mut start = vec![
+            Neighbor {
+                index: !0,
+                distance: !0,
+            };
+            n_start
+        ];
+        let found = hnsw
+            .nearest(&query, n_start, &mut searcher, &mut start)
+            .len();
+        // see `search`, only an exact scan finds unreachable items
+        if found < n_start {
+            start = self.exact_neighbors(&query, &hnsw, &quantizer, hnsw.len());
         }
-    }
 
-    pub fn rebuild(&self) {
-        let new_index = VectorIndex::new();
-        let vectors = self.vectors.read().unwrap();
-        let removed = self.removed.read().unwrap();
-        for (id, v) in vectors.iter() {
-            if !removed.contains(id) {
-                new_index.insert(v.clone(), id.clone());
+        let mut visited: HashSet<usize> = start.iter().map(|n| n.index).collect();
+        let mut frontier: BinaryHeap<Reverse<(u64, usize)>> = start
+            .iter()
+            .map(|n| Reverse((n.distance, n.index)))
+            .collect();
+        let mut neighbors = vec![];
+        while let Some(Reverse((distance, index))) = frontier.pop() {
+            let distance = from_ordered_bits(distance);
+            if distance > radius || neighbors.len() >= max_results {
+                break;
+            }
+            if !removed.contains(&index) && accept(&ids[index]) {
+                neighbors.push(AnnNeighbor {
+                    id: ids[index].clone(),
+                    index,
+                    distance,
+                    similarity: self.config.metric().similarity(distance),
+                });
+            }
+            for next in hnsw.neighbors(index) {
+                if visited.insert(next) {
+                    let distance = quantizer.distance(&query, hnsw.feature(next));
+                    frontier.push(Reverse((distance, next)));
+                }
             }
         }
+        neighbors.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        neighbors
+    }
 
-        drop(vectors);
+    fn remove(&self, id: String) -> bool {
+        let mut positions = self.positions.write().unwrap();
+        let mut removed = self.removed.write().unwrap();
+        let index = match positions.remove(&id) {
+            Some(index) => index,
+            None => return false,
+        };
+        removed.insert(index);
+        self.full_vectors.remove(&id);
+        self.record_change(IndexChange::Remove(id));
+        let needs_rebuild = removed.len() > MAX_REMOVED_BEFORE_REBUILD;
         drop(removed);
+        drop(positions);
+        if needs_rebuild {
+            self.rebuild_in_background();
+        }
+        true
+    }
 
-        // replace all data structures inplace
-        let mut searcher_old = self.searcher.write().unwrap();
-        let mut hnsw_old = self.hnsw.write().unwrap();
-        let mut vectors_old = self.vectors.write().unwrap();
-        let mut removed_old = self.removed.write().unwrap();
+    fn contains(&self, id: &str) -> bool {
+        self.positions.read().unwrap().contains_key(id)
+    }
 
-        let searcher_new = new_index.searcher.read().unwrap();
-        mem::replace(&mut *searcher_old, (*searcher_new).clone());
+    fn ids(&self) -> Vec<String> {
+        self.positions.read().unwrap().keys().cloned().collect()
+    }
 
-        let hnsw_new = new_index.hnsw.read().unwrap();
-        mem::replace(&mut *hnsw_old, (*hnsw_new).clone());
+    fn vector(&self, id: &str) -> Option<Vec<f64>> {
+        let hnsw = self.hnsw.read().unwrap();
+        let quantizer = self.quantizer.read().unwrap();
+        let positions = self.positions.read().unwrap();
+        let index = *positions.get(id)?;
+        Some(quantizer.decode(hnsw.feature(index)))
+    }
 
-        let vectors_new = new_index.vectors.read().unwrap();
-        mem::replace(&mut *vectors_old, (*vectors_new).clone());
+    fn full_vector(&self, id: &str) -> Option<Vec<f64>> {
+        self.full_vectors.get(id)
+    }
+}
 
-        removed_old.clear();
+impl Default for VectorIndex {
+    fn default() -> Self {
+        Self::new(&IndexConfig::default())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::index::test_utils::random_vectors;
 
     #[test]
     fn test_vector_index() {
-        let index = VectorIndex::new();
+        let index = VectorIndex::default();
         let features: [&[f64]; 9] = [
             &[0.0, 0.0, 0.0, 0.0],
             &[0.0, 0.0, 0.0, 1.0],
@@ -142,22 +854,403 @@ mod tests {
             &[1.0, 0.0, 0.0, 1.0],
         ];
 
-        let mut i = 0;
-        for &feature in &features {
+        for (i, &feature) in features.iter().enumerate() {
             let v = feature.to_vec();
             index.insert(v, i.to_string());
-            i += 1;
         }
 
-        let neighbors = index.search(&features[0].to_vec().clone());
+        let neighbors = index.search(features[0], 8, DEFAULT_EF);
         assert_eq!(neighbors[0].id, "0".to_string());
 
         index.remove("0".to_string());
-        let neighbors = index.search(&features[0].to_vec().clone());
+        let neighbors = index.search(features[0], 8, DEFAULT_EF);
         assert_eq!(neighbors[0].id, "1".to_string());
 
         index.rebuild();
-        let neighbors = index.search(&features[0].to_vec().clone());
+        let neighbors = index.search(features[0], 8, DEFAULT_EF);
         assert_eq!(neighbors[0].id, "1".to_string());
     }
+
+    #[test]
+    fn test_save_load() {
+        let index = VectorIndex::default();
+        for i in 0..10 {
+            index.insert(vec![i as f64, i as f64], i.to_string());
+        }
+        index.remove("9".to_string());
+
+        let mut buffer = Vec::new();
+        index.save(&mut buffer).unwrap();
+        let loaded = VectorIndex::load(buffer.as_slice()).unwrap();
+
+        assert_eq!(loaded.ids.read().unwrap().len(), 10);
+        assert!(loaded.removed.read().unwrap().contains(&9));
+        assert!(!loaded.contains("9"));
+        assert!(loaded.contains("1"));
+        let ids = |neighbors: Vec<AnnNeighbor>| -> Vec<String> {
+            neighbors.into_iter().map(|n| n.id).collect()
+        };
+        assert_eq!(
+            ids(loaded.search(&[9.0, 9.0], 8, DEFAULT_EF)),
+            ids(index.search(&[9.0, 9.0], 8, DEFAULT_EF))
+        );
+    }
+
+    #[test]
+    fn test_search_n_results() {
+        let index = VectorIndex::default();
+        for i in 0..30 {
+            index.insert(vec![i as f64, 0.0], i.to_string());
+        }
+        for i in 0..5 {
+            index.remove(i.to_string());
+        }
+
+        let neighbors = index.search(&[0.0, 0.0], 20, 1);
+        assert_eq!(neighbors.len(), 20);
+        assert_eq!(neighbors[0].id, "5".to_string());
+        assert!(neighbors
+            .iter()
+            .all(|n| n.id.parse::<usize>().unwrap() >= 5));
+
+        let neighbors = index.search(&[0.0, 0.0], 100, DEFAULT_EF);
+        assert_eq!(neighbors.len(), 25);
+    }
+
+    #[test]
+    fn test_replace_duplicate_ids() {
+        for backend in &[IndexBackend::Hnsw, IndexBackend::Flat, IndexBackend::IvfPq] {
+            let index = CollectionIndex::new(&IndexConfig {
+                backend: *backend,
+                ..Default::default()
+            });
+            for i in 0..10 {
+                index.insert(vec![i as f64, 0.0], i.to_string());
+            }
+            index.insert(vec![100.0, 0.0], "3".to_string());
+
+            let neighbors = index.search(&[3.0, 0.0], 20, DEFAULT_EF);
+            assert_eq!(neighbors.len(), 10);
+            assert_eq!(neighbors.iter().filter(|n| n.id == "3").count(), 1);
+            assert_eq!(neighbors.last().unwrap().id, "3");
+
+            assert!(index.remove("3".to_string()));
+            assert!(!index.remove("3".to_string()));
+            assert!(!index.remove("unknown".to_string()));
+            assert!(!index.contains("3"));
+            assert!(index.vector("3").is_none());
+            assert_eq!(index.vector("4"), Some(vec![4.0, 0.0]));
+            assert_eq!(index.search(&[3.0, 0.0], 20, DEFAULT_EF).len(), 9);
+        }
+    }
+
+    #[test]
+    fn test_rebuild_after_removals() {
+        let index = VectorIndex::default();
+        for i in 0..150 {
+            index.insert(vec![i as f64, 0.0], i.to_string());
+        }
+        for i in 0..=MAX_REMOVED_BEFORE_REBUILD {
+            assert!(index.remove(i.to_string()));
+        }
+        index.wait_for_rebuild();
+        assert!(index.removed.read().unwrap().is_empty());
+        assert_eq!(index.ids.read().unwrap().len(), 150 - 101);
+        assert!(index.contains("120"));
+        assert_eq!(index.search(&[0.0, 0.0], 1, DEFAULT_EF)[0].id, "101");
+
+        // replaced vectors are dropped too
+        for round in 1..=3 {
+            for i in 101..150 {
+                index.insert(vec![i as f64, round as f64], i.to_string());
+            }
+        }
+        index.wait_for_rebuild();
+        assert!(index.removed.read().unwrap().len() <= MAX_REMOVED_BEFORE_REBUILD);
+        assert_eq!(index.search(&[0.0, 0.0], 100, DEFAULT_EF).len(), 49);
+    }
+
+    #[test]
+    fn test_training_during_rebuild() {
+        let index = VectorIndex::new(&IndexConfig {
+            precision: VectorPrecision::Int8,
+            hnsw: HnswConfig {
+                ef_construction: 32,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let point = |i: usize| vec![(i % 40) as f64, (i / 40) as f64];
+        for i in 0..10 {
+            index.insert(point(i), i.to_string());
+        }
+        let (quantizer, items) = index.begin_rebuild().unwrap();
+        let (hnsw, ids) = index.build_graph(quantizer.clone(), items);
+        for i in 10..INT8_TRAINING_SIZE {
+            index.insert(point(i), i.to_string());
+        }
+        assert!(!index.quantizer.read().unwrap().needs_training());
+        index.finish_rebuild(quantizer, hnsw, ids);
+
+        // the scales learned in the meantime are kept
+        assert!(!index.quantizer.read().unwrap().needs_training());
+        let hnsw = index.hnsw.read().unwrap();
+        assert_eq!(hnsw.len(), INT8_TRAINING_SIZE);
+        assert!((0..hnsw.len()).all(|i| matches!(hnsw.feature(i), StoredVector::Int8(_))));
+        drop(hnsw);
+        assert_eq!(index.search(&[17.0, 9.0], 1, DEFAULT_EF)[0].id, "377");
+    }
+
+    #[test]
+    fn test_changes_during_rebuild() {
+        let index = VectorIndex::default();
+        for i in 0..20 {
+            index.insert(vec![i as f64, 0.0], i.to_string());
+        }
+        index.remove("0".to_string());
+
+        let (quantizer, items) = index.begin_rebuild().unwrap();
+        assert!(index.begin_rebuild().is_none());
+        let (hnsw, ids) = index.build_graph(quantizer.clone(), items);
+        // the live index keeps working while the new graph is built
+        index.insert(vec![-1.0, 0.0], "new".to_string());
+        index.insert(vec![-0.5, 0.0], "5".to_string());
+        index.remove("1".to_string());
+        assert_eq!(index.search(&[0.0, 0.0], 1, DEFAULT_EF)[0].id, "5");
+        index.finish_rebuild(quantizer, hnsw, ids);
+
+        assert!(index.contains("new"));
+        assert!(!index.contains("0"));
+        assert!(!index.contains("1"));
+        // the old node of "5" and "1" are removed
+        assert_eq!(index.removed.read().unwrap().len(), 2);
+        let neighbors = index.search(&[0.0, 0.0], 100, DEFAULT_EF);
+        assert_eq!(neighbors.len(), 19);
+        assert_eq!(neighbors[0].id, "5");
+        assert!(index.rebuild_changes.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_search_filtered() {
+        for backend in &[IndexBackend::Hnsw, IndexBackend::Flat, IndexBackend::IvfPq] {
+            let index = CollectionIndex::new(&IndexConfig {
+                backend: *backend,
+                ..Default::default()
+            });
+            for i in 0..200 {
+                index.insert(vec![i as f64, 0.0], i.to_string());
+            }
+            // only one in 50 ids passes, far more than the first round fetches
+            let accept = |id: &str| id.parse::<usize>().unwrap() % 50 == 7;
+            let neighbors = index.search_filtered(&[0.0, 0.0], 3, DEFAULT_EF, &accept);
+            let ids: Vec<String> = neighbors.into_iter().map(|n| n.id).collect();
+            assert_eq!(ids, ["7", "57", "107"]);
+
+            let neighbors = index.search_filtered(&[0.0, 0.0], 10, DEFAULT_EF, &accept);
+            assert_eq!(neighbors.len(), 4);
+        }
+    }
+
+    #[test]
+    fn test_search_range() {
+        let point = |i: usize| vec![(i % 40) as f64, (i / 40) as f64];
+        let query = [20.0, 12.0];
+        let within =
+            |i: &usize| DistanceMetric::Euclidean.float_distance(&point(*i), &query) <= 8.0;
+        let expected = (0..1000).filter(within).count();
+        // far more results than the first round fetches
+        assert!(expected > 2 * DEFAULT_EF);
+        for backend in &[IndexBackend::Hnsw, IndexBackend::Flat, IndexBackend::IvfPq] {
+            let index = CollectionIndex::new(&IndexConfig {
+                backend: *backend,
+                ..Default::default()
+            });
+            for i in 0..1000 {
+                index.insert(point(i), i.to_string());
+            }
+            let all = |_: &str| true;
+            // hnsw may miss a few neighbors
+            let neighbors = index.search_range(&query, 8.0, 1000, DEFAULT_EF, &all);
+            assert!(neighbors.len() * 10 >= expected * 9, "{:?}", backend);
+            assert!(neighbors.len() <= expected);
+            assert!(neighbors.iter().all(|n| n.distance <= 8.0));
+            // the other backends scan every vector, without a cap on the results
+            if *backend != IndexBackend::Hnsw {
+                let neighbors = index.search_range(&query, 8.0, usize::MAX, DEFAULT_EF, &all);
+                assert_eq!(neighbors.len(), expected, "{:?}", backend);
+            }
+
+            let neighbors = index.search_range(&query, 8.0, 50, DEFAULT_EF, &all);
+            assert_eq!(neighbors.len(), 50);
+            assert!(neighbors.windows(2).all(|w| w[0].distance <= w[1].distance));
+
+            let even = |id: &str| id.parse::<usize>().unwrap() % 2 == 0;
+            let neighbors = index.search_range(&query, 8.0, 1000, DEFAULT_EF, &even);
+            let expected_even = (0..1000).filter(within).filter(|i| i % 2 == 0).count();
+            assert!(neighbors.len() * 10 >= expected_even * 9, "{:?}", backend);
+            assert!(neighbors.len() <= expected_even);
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let a = [1.0, 0.0];
+        let b = [10.0, 1.0];
+        let c = [0.5, 0.5];
+        assert!(
+            DistanceMetric::Euclidean.float_distance(&a, &c)
+                < DistanceMetric::Euclidean.float_distance(&a, &b)
+        );
+        assert!(
+            DistanceMetric::Cosine.float_distance(&a, &b)
+                < DistanceMetric::Cosine.float_distance(&a, &c)
+        );
+        assert!(
+            DistanceMetric::InnerProduct.float_distance(&a, &b)
+                < DistanceMetric::InnerProduct.float_distance(&a, &c)
+        );
+        assert_eq!(DistanceMetric::Cosine.float_distance(&a, &[3.0, 0.0]), 0.0);
+        assert_eq!(
+            DistanceMetric::Hamming.float_distance(&[1.0, 0.0, 1.0, 1.0], &[1.0, 1.0, 0.0, 1.0]),
+            2.0
+        );
+
+        let values = [-3.5, -1.0, -0.0, 0.0, 0.25, 2.0, 1e10];
+        for pair in values.windows(2) {
+            assert!(to_ordered_bits(pair[0]) <= to_ordered_bits(pair[1]));
+        }
+        for &value in &values {
+            assert_eq!(from_ordered_bits(to_ordered_bits(value)), value);
+        }
+
+        for metric in &[
+            DistanceMetric::Euclidean,
+            DistanceMetric::Cosine,
+            DistanceMetric::InnerProduct,
+        ] {
+            let close = metric.similarity(metric.float_distance(&a, &[0.9, 0.1]));
+            let far = metric.similarity(metric.float_distance(&a, &[-1.0, 0.0]));
+            assert!((0.0..=1.0).contains(&close));
+            assert!((0.0..=1.0).contains(&far));
+            assert!(close > far);
+        }
+    }
+
+    #[test]
+    fn test_search_cosine() {
+        let index = VectorIndex::new(&IndexConfig {
+            metric: Some(DistanceMetric::Cosine),
+            ..Default::default()
+        });
+        for i in 0..8 {
+            index.insert(vec![0.5, 0.5 + i as f64], format!("filler{}", i));
+        }
+        index.insert(vec![10.0, 1.0], "same_direction".to_string());
+        index.insert(vec![0.9, 0.0], "close".to_string());
+
+        let neighbors = index.search(&[1.0, 0.1], 8, DEFAULT_EF);
+        assert_eq!(neighbors[0].id, "same_direction".to_string());
+        assert!(neighbors[0].distance < 1e-6);
+        let hnsw = index.hnsw.read().unwrap();
+        let quantizer = index.quantizer.read().unwrap();
+        for neighbor in &neighbors {
+            let stored = quantizer.decode(hnsw.feature(neighbor.index));
+            let exact = DistanceMetric::Cosine.float_distance(&[1.0, 0.1], &stored);
+            assert!((neighbor.distance - exact).abs() < 1e-6);
+        }
+        assert!(neighbors[0].similarity > neighbors[1].similarity);
+    }
+
+    #[test]
+    fn test_int8_training() {
+        let index = VectorIndex::new(&IndexConfig {
+            precision: VectorPrecision::Int8,
+            hnsw: HnswConfig {
+                ef_construction: 32,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        for i in 0..INT8_TRAINING_SIZE {
+            index.insert(vec![(i % 40) as f64, (i / 40) as f64], i.to_string());
+        }
+        assert!(!index.quantizer.read().unwrap().needs_training());
+        let hnsw = index.hnsw.read().unwrap();
+        assert_eq!(hnsw.len(), INT8_TRAINING_SIZE);
+        assert!(matches!(hnsw.feature(0), StoredVector::Int8(_)));
+        drop(hnsw);
+
+        let neighbors = index.search(&[17.0, 9.0], 1, DEFAULT_EF);
+        assert_eq!(neighbors[0].id, "377");
+    }
+
+    #[test]
+    fn test_recall_against_flat() {
+        let config = IndexConfig::default();
+        let hnsw = CollectionIndex::new(&config);
+        let flat = CollectionIndex::new(&flat_config());
+
+        let mut vectors = random_vectors(520, 8, 0x2545_f491_4f6c_dd1d);
+        let queries = vectors.split_off(500);
+        for (i, v) in vectors.into_iter().enumerate() {
+            hnsw.insert(v.clone(), i.to_string());
+            flat.insert(v, i.to_string());
+        }
+
+        let mut found = 0;
+        for query in queries {
+            let expected: HashSet<String> = flat
+                .search(&query, 10, DEFAULT_EF)
+                .into_iter()
+                .map(|n| n.id)
+                .collect();
+            found += hnsw
+                .search(&query, 10, DEFAULT_EF)
+                .into_iter()
+                .filter(|n| expected.contains(&n.id))
+                .count();
+        }
+        assert!(found as f64 / 200.0 > 0.9);
+
+        let mut buffer = Vec::new();
+        flat.save(&mut buffer).unwrap();
+        let loaded = CollectionIndex::load(&flat_config(), buffer.as_slice()).unwrap();
+        assert_eq!(loaded.len(), 500);
+    }
+
+    fn flat_config() -> IndexConfig {
+        IndexConfig {
+            backend: IndexBackend::Flat,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_hnsw_connections() {
+        for &connections in &[
+            HnswConnections::M6,
+            HnswConnections::M12,
+            HnswConnections::M24,
+            HnswConnections::M48,
+        ] {
+            let index = VectorIndex::new(&IndexConfig {
+                hnsw: HnswConfig {
+                    connections,
+                    ef_construction: 50,
+                },
+                ..Default::default()
+            });
+            for i in 0..50 {
+                index.insert(vec![(i % 7) as f64, (i / 7) as f64], i.to_string());
+            }
+            let neighbors = index.search(&[3.0, 3.0], 5, DEFAULT_EF);
+            assert_eq!(neighbors.len(), 5);
+            assert_eq!(neighbors[0].id, "24".to_string());
+
+            let mut buffer = Vec::new();
+            index.save(&mut buffer).unwrap();
+            let loaded = VectorIndex::load(buffer.as_slice()).unwrap();
+            assert_eq!(loaded.config.hnsw.connections, connections);
+        }
+    }
 }
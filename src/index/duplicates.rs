@@ -0,0 +1,146 @@
+use crate::index::db::{CollectionIndex, SearchIndex, DEFAULT_EF};
+use crate::state::parallel::parallel_map;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Two images closer to each other than the threshold, `a < b`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DuplicatePair {
+    pub a: String,
+    pub b: String,
+    pub distance: f64,
+}
+
+/// Images connected by pairs of near duplicates. Not every two images of a
+/// group need to be closer than the threshold, only the listed pairs are.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DuplicateGroup {
+    pub ids: Vec<String>,
+    pub pairs: Vec<DuplicatePair>,
+}
+
+fn find_root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+/// Joins the index with itself: a range search around every image finds the
+/// pairs within `threshold`, which are then clustered into connected
+/// components. Groups are sorted by size, largest first.
+pub fn find_duplicates(index: &CollectionIndex, threshold: f64) -> Vec<DuplicateGroup> {
+    let mut ids = index.ids();
+    ids.sort_unstable();
+    let positions: HashMap<&str, usize> = ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (id.as_str(), i))
+        .collect();
+
+    let found = parallel_map(ids.clone(), |id| {
+        // the image may have been removed since listing the ids
+        let v = match index.vector(&id) {
+            Some(v) => v,
+            None => return vec![],
+        };
+        index
+            // images added since listing the ids are left for the next run.
+            // Results are not capped, a group can have any number of images
+            .search_range(&v, threshold, usize::MAX, DEFAULT_EF, &|other| {
+                other != id && positions.contains_key(other)
+            })
+            .into_iter()
+            .map(|neighbor| {
+                let (a, b) = if id < neighbor.id {
+                    (id.clone(), neighbor.id)
+                } else {
+                    (neighbor.id, id.clone())
+                };
+                DuplicatePair {
+                    a,
+                    b,
+                    distance: neighbor.distance,
+                }
+            })
+            .collect()
+    });
+    // approximate searches may find a pair from one side only
+    let mut pairs: HashMap<(String, String), f64> = HashMap::new();
+    for pair in found.into_iter().flatten() {
+        let distance = pairs.entry((pair.a, pair.b)).or_insert(pair.distance);
+        *distance = distance.min(pair.distance);
+    }
+
+    let mut parents: Vec<usize> = (0..ids.len()).collect();
+    for (a, b) in pairs.keys() {
+        let (root_a, root_b) = (
+            find_root(&mut parents, positions[a.as_str()]),
+            find_root(&mut parents, positions[b.as_str()]),
+        );
+        parents[root_a] = root_b;
+    }
+
+    let mut groups: HashMap<usize, DuplicateGroup> = HashMap::new();
+    for ((a, b), distance) in pairs {
+        let root = find_root(&mut parents, positions[a.as_str()]);
+        let group = groups.entry(root).or_insert(DuplicateGroup {
+            ids: vec![],
+            pairs: vec![],
+        });
+        group.pairs.push(DuplicatePair { a, b, distance });
+    }
+    for (i, id) in ids.iter().enumerate() {
+        if let Some(group) = groups.get_mut(&find_root(&mut parents, i)) {
+            group.ids.push(id.clone());
+        }
+    }
+
+    let mut groups: Vec<DuplicateGroup> = groups.into_values().collect();
+    for group in groups.iter_mut() {
+        group.pairs.sort_by(|x, y| (&x.a, &x.b).cmp(&(&y.a, &y.b)));
+    }
+    groups.sort_by(|x, y| y.ids.len().cmp(&x.ids.len()).then(x.ids.cmp(&y.ids)));
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::db::{IndexBackend, IndexConfig};
+
+    #[test]
+    fn test_find_duplicates() {
+        for backend in &[IndexBackend::Hnsw, IndexBackend::Flat, IndexBackend::IvfPq] {
+            let index = CollectionIndex::new(&IndexConfig {
+                backend: *backend,
+                ..Default::default()
+            });
+            // a chain of three images, a pair and images far from everything
+            index.insert(vec![0.0, 0.0], "a1".into());
+            index.insert(vec![0.5, 0.0], "a2".into());
+            index.insert(vec![1.0, 0.0], "a3".into());
+            index.insert(vec![10.0, 10.0], "b1".into());
+            index.insert(vec![10.0, 10.1], "b2".into());
+            for i in 0..20 {
+                index.insert(vec![100.0 * i as f64, -50.0], format!("c{}", i));
+            }
+
+            let groups = find_duplicates(&index, 0.6);
+            assert_eq!(groups.len(), 2, "{:?}", backend);
+            assert_eq!(groups[0].ids, ["a1", "a2", "a3"]);
+            let pairs: Vec<_> = groups[0]
+                .pairs
+                .iter()
+                .map(|p| (p.a.as_str(), p.b.as_str()))
+                .collect();
+            assert_eq!(pairs, [("a1", "a2"), ("a2", "a3")]);
+            assert_eq!(groups[1].ids, ["b1", "b2"]);
+            assert!((groups[1].pairs[0].distance - 0.1).abs() < 1e-3);
+
+            assert!(find_duplicates(&index, 0.01).is_empty());
+        }
+    }
+}
@@ -1,3 +1,7 @@
+use crate::index::db::IndexConfig;
+use crate::index::metadata::{json_string, Filter, Metadata};
+use crate::index::mmr::Mmr;
+use crate::index::rerank::Rerank;
 use crate::state::app::{CollectionName, GenericModelConfig};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -10,6 +14,9 @@ pub enum Event {
     SearchImage(SearchImage),
     UpsertCollection(UpsertCollection),
     RemoveCollection(RemoveCollection),
+    FitPca(FitPca),
+    ImageIndexed(ImageIndexed),
+    UploadQueued(UploadQueued),
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
@@ -21,6 +28,8 @@ pub struct ImageBytes {
 pub enum ImageSource {
     ImageBytes(ImageBytes),
     Url(Url),
+    // an embedding computed elsewhere, used as is instead of running the model
+    Vector(Vec<f32>),
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
@@ -28,13 +37,201 @@ pub struct AddImage {
     pub source: ImageSource,
     pub collection_name: CollectionName,
     pub id: String,
+    // attributes to filter searches on, replaced when the id is added again
+    #[serde(default, with = "json_string")]
+    #[schemars(with = "Metadata")]
+    pub metadata: Metadata,
+}
+
+/// Logged once an `AddImage` job has run. Replaying it indexes the features
+/// computed back then instead of downloading and embedding the image again.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImageIndexed {
+    pub collection_name: CollectionName,
+    pub id: String,
+    // in the space of the model, None if the job failed
+    pub features: Option<Vec<f64>>,
+    #[serde(default, with = "json_string")]
+    pub metadata: Metadata,
+}
+
+/// How an `AddImage` of uploaded bytes is written to the log: the bytes are
+/// saved to the file `upload` next to the log, the image in `add_image` is empty.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UploadQueued {
+    pub add_image: AddImage,
+    pub upload: String,
+}
+
+fn default_weight() -> f64 {
+    1.0
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct WeightedQuery {
+    pub query: QuerySource,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+}
+
+/// Searches like `source` and the `positive` examples and unlike the
+/// `negative` ones, their embeddings are combined into one query vector.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct SearchImage {
+    // weighs 1, optional if there are positive examples
+    #[serde(default)]
+    pub source: Option<ImageSource>,
+    #[serde(default)]
+    pub positive: Vec<WeightedQuery>,
+    #[serde(default)]
+    pub negative: Vec<WeightedQuery>,
+    pub collection_name: CollectionName,
+    pub n_results: usize,
+    // candidate pool size of the search, larger is more accurate but slower
+    #[serde(default)]
+    pub ef: Option<usize>,
+    // only return images whose metadata matches
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    // re-rank the nearest neighbors to return varied images
+    #[serde(default)]
+    pub diversify: Option<Mmr>,
+    // over-fetch candidates and re-rank them by their exact distance
+    #[serde(default)]
+    pub rerank: Option<Rerank>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum QuerySource {
+    Source(ImageSource),
+    // an image which is already in the collection, excluded from its results
+    Id(String),
+}
+
+impl QuerySource {
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            QuerySource::Id(id) => Some(id),
+            QuerySource::Source(_) => None,
+        }
+    }
+}
+
+/// Many searches in one collection, answered in the order of `queries`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct BatchSearch {
+    pub collection_name: CollectionName,
+    pub queries: Vec<QuerySource>,
+    pub n_results: usize,
+    #[serde(default)]
+    pub ef: Option<usize>,
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    #[serde(default)]
+    pub rerank: Option<Rerank>,
+}
+
+/// One search over several collections whose vectors are comparable, the
+/// results of all of them are ranked together.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct FederatedSearch {
+    pub collection_names: Vec<CollectionName>,
     pub source: ImageSource,
+    pub n_results: usize,
+    #[serde(default)]
+    pub ef: Option<usize>,
+    #[serde(default)]
+    pub filter: Option<Filter>,
+}
+
+/// Starts a job grouping the near-duplicate images of a collection.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct FindDuplicates {
     pub collection_name: CollectionName,
+    // images closer than this are duplicates
+    pub threshold: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GetDuplicates {
+    pub collection_name: CollectionName,
+}
+
+/// Fits the PCA of a collection on a sample of images or vectors instead of
+/// its first indexed images.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct FitPca {
+    pub collection_name: CollectionName,
+    pub sample: Vec<ImageSource>,
+}
+
+fn default_n_iterations() -> usize {
+    20
+}
+
+/// Starts a job grouping the images of a collection into `k` clusters with
+/// k-means.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ClusterCollection {
+    pub collection_name: CollectionName,
+    pub k: usize,
+    #[serde(default = "default_n_iterations")]
+    pub n_iterations: usize,
+    // the same seed gives the same clusters for the same images
+    #[serde(default)]
+    pub seed: u64,
+}
+
+fn default_n_central() -> usize {
+    5
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GetClusters {
+    pub collection_name: CollectionName,
+    // most central images listed for every cluster
+    #[serde(default = "default_n_central")]
+    pub n_central: usize,
+}
+
+/// All images of one cluster, the most central first.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GetClusterMembers {
+    pub collection_name: CollectionName,
+    // position of the cluster in the `GetClusters` response
+    pub cluster: usize,
+}
+
+/// All images closer to the query than `radius`, e.g. to find near duplicates.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RangeSearch {
+    pub collection_name: CollectionName,
+    pub query: QuerySource,
+    // in units of the distance metric of the collection
+    pub radius: f64,
+    // at most 10000 results are returned
+    #[serde(default)]
+    pub max_results: Option<usize>,
+    #[serde(default)]
+    pub ef: Option<usize>,
+    #[serde(default)]
+    pub filter: Option<Filter>,
+}
+
+/// Search for images similar to one which is already in the collection.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SearchSimilar {
+    pub collection_name: CollectionName,
+    pub id: String,
     pub n_results: usize,
+    #[serde(default)]
+    pub ef: Option<usize>,
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    #[serde(default)]
+    pub diversify: Option<Mmr>,
+    #[serde(default)]
+    pub rerank: Option<Rerank>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -47,6 +244,8 @@ pub struct RemoveImage {
 pub struct UpsertCollection {
     pub name: String,
     pub config: GenericModelConfig,
+    #[serde(default)]
+    pub index_config: IndexConfig,
 }
 
 #[derive(Clone, Serialize, Deserialize, JsonSchema)]
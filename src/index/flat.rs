@@ -0,0 +1,285 @@
+use crate::index::db::{AnnNeighbor, IndexConfig, IndexedVectors, SearchIndex};
+use crate::index::quantization::{Quantizer, INT8_TRAINING_SIZE};
+use crate::index::rerank::FullVectors;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+// below this many vectors per thread spawning threads costs more than it saves
+const MIN_VECTORS_PER_THREAD: usize = 4096;
+
+/// Exact nearest neighbor search by scanning all vectors. Meant for small
+/// collections and as ground truth when measuring the recall of `VectorIndex`.
+#[derive(Clone)]
+pub struct FlatIndex {
+    pub config: IndexConfig,
+    pub quantizer: Arc<RwLock<Quantizer>>,
+    pub vectors: Arc<RwLock<IndexedVectors>>,
+    // position of every id in `vectors`
+    pub positions: Arc<RwLock<HashMap<String, usize>>>,
+    full_vectors: FullVectors,
+}
+
+impl FlatIndex {
+    pub fn new(config: &IndexConfig) -> Self {
+        FlatIndex {
+            config: config.clone(),
+            quantizer: Arc::new(RwLock::new(Quantizer::new(
+                config.metric(),
+                config.precision,
+            ))),
+            vectors: Arc::new(RwLock::new(Vec::new())),
+            positions: Arc::new(RwLock::new(HashMap::new())),
+            full_vectors: FullVectors::new(config),
+        }
+    }
+
+    pub fn save<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        let quantizer = self.quantizer.read().map_err(|_| "RwLock Error")?;
+        let vectors = self.vectors.read().map_err(|_| "RwLock Error")?;
+        bincode::serialize_into(
+            writer,
+            &(&self.config, &*quantizer, &*vectors, &self.full_vectors),
+        )?;
+        Ok(())
+    }
+
+    pub fn load<R: Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        let (config, quantizer, vectors, full_vectors): (
+            IndexConfig,
+            Quantizer,
+            IndexedVectors,
+            FullVectors,
+        ) = bincode::deserialize_from(reader)?;
+        let positions = vectors
+            .iter()
+            .enumerate()
+            .map(|(index, (id, _))| (id.clone(), index))
+            .collect();
+        Ok(FlatIndex {
+            config,
+            quantizer: Arc::new(RwLock::new(quantizer)),
+            vectors: Arc::new(RwLock::new(vectors)),
+            positions: Arc::new(RwLock::new(positions)),
+            full_vectors,
+        })
+    }
+
+    /// Scans the vectors in parallel, `select` picks the results among the
+    /// distances of every thread and then among the merged ones.
+    fn scan<F>(
+        &self,
+        v: &[f64],
+        accept: &(dyn Fn(&str) -> bool + Sync),
+        select: F,
+    ) -> Vec<AnnNeighbor>
+    where
+        F: Fn(Vec<(f64, usize)>) -> Vec<(f64, usize)> + Sync,
+    {
+        let quantizer = self.quantizer.read().unwrap();
+        let vectors = self.vectors.read().unwrap();
+        let metric = self.config.metric();
+        let query = quantizer.encode_query(v);
+        let (query, quantizer, select) = (&query, &*quantizer, &select);
+        let n_threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(vectors.len() / MIN_VECTORS_PER_THREAD)
+            .max(1);
+        let chunk_size = (vectors.len() / n_threads).max(1);
+
+        let candidates: Vec<(f64, usize)> = thread::scope(|scope| {
+            let handles: Vec<_> = vectors
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(n, chunk)| {
+                    scope.spawn(move || {
+                        let distances = chunk
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, (id, _))| accept(id))
+                            .map(|(i, (_, vector))| {
+                                (quantizer.float_distance(query, vector), n * chunk_size + i)
+                            })
+                            .collect();
+                        select(distances)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        let mut candidates = select(candidates);
+        candidates.sort_by(compare_distance);
+
+        candidates
+            .into_iter()
+            .map(|(distance, index)| AnnNeighbor {
+                id: vectors[index].0.clone(),
+                index,
+                distance,
+                similarity: metric.similarity(distance),
+            })
+            .collect()
+    }
+}
+
+impl SearchIndex for FlatIndex {
+    /// Adding an id which is already in the index replaces its vector.
+    fn insert(&self, v: Vec<f64>, id: String) {
+        let mut quantizer = self.quantizer.write().unwrap();
+        let mut vectors = self.vectors.write().unwrap();
+        let mut positions = self.positions.write().unwrap();
+        let stored = quantizer.encode(&v);
+        self.full_vectors.insert(&id, &v);
+        match positions.get(&id) {
+            Some(&index) => vectors[index].1 = stored,
+            None => {
+                positions.insert(id.clone(), vectors.len());
+                vectors.push((id, stored));
+            }
+        }
+        if quantizer.needs_training() && vectors.len() >= INT8_TRAINING_SIZE {
+            quantizer.train_and_encode(vectors.iter_mut().map(|(_, v)| v));
+        }
+    }
+
+    /// Scans the vectors in parallel, every thread keeps its own top `k` and
+    /// the results are merged at the end. `ef` is ignored.
+    fn search(&self, v: &[f64], k: usize, ef: usize) -> Vec<AnnNeighbor> {
+        self.search_filtered(v, k, ef, &|_| true)
+    }
+
+    /// Skips vectors which are not accepted while scanning.
+    fn search_filtered(
+        &self,
+        v: &[f64],
+        k: usize,
+        _ef: usize,
+        accept: &(dyn Fn(&str) -> bool + Sync),
+    ) -> Vec<AnnNeighbor> {
+        self.scan(v, accept, |distances| top_k(distances, k))
+    }
+
+    /// A single scan which keeps every vector within `radius`, the cost does
+    /// not depend on `max_results`.
+    fn search_range(
+        &self,
+        v: &[f64],
+        radius: f64,
+        max_results: usize,
+        _ef: usize,
+        accept: &(dyn Fn(&str) -> bool + Sync),
+    ) -> Vec<AnnNeighbor> {
+        self.scan(v, accept, |mut distances| {
+            distances.retain(|&(distance, _)| distance <= radius);
+            top_k(distances, max_results)
+        })
+    }
+
+    fn remove(&self, id: String) -> bool {
+        let mut vectors = self.vectors.write().unwrap();
+        let mut positions = self.positions.write().unwrap();
+        let index = match positions.remove(&id) {
+            Some(index) => index,
+            None => return false,
+        };
+        vectors.swap_remove(index);
+        self.full_vectors.remove(&id);
+        if let Some((moved, _)) = vectors.get(index) {
+            positions.insert(moved.clone(), index);
+        }
+        true
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.positions.read().unwrap().contains_key(id)
+    }
+
+    fn ids(&self) -> Vec<String> {
+        let vectors = self.vectors.read().unwrap();
+        vectors.iter().map(|(id, _)| id.clone()).collect()
+    }
+
+    fn vector(&self, id: &str) -> Option<Vec<f64>> {
+        let quantizer = self.quantizer.read().unwrap();
+        let vectors = self.vectors.read().unwrap();
+        let index = *self.positions.read().unwrap().get(id)?;
+        Some(quantizer.decode(&vectors[index].1))
+    }
+
+    fn full_vector(&self, id: &str) -> Option<Vec<f64>> {
+        self.full_vectors.get(id)
+    }
+}
+
+pub(crate) fn compare_distance(a: &(f64, usize), b: &(f64, usize)) -> Ordering {
+    a.0.total_cmp(&b.0)
+}
+
+pub(crate) fn top_k(mut distances: Vec<(f64, usize)>, k: usize) -> Vec<(f64, usize)> {
+    if k == 0 {
+        return vec![];
+    }
+    if distances.len() > k {
+        distances.select_nth_unstable_by(k - 1, compare_distance);
+        distances.truncate(k);
+    }
+    distances
+}
+
+impl Default for FlatIndex {
+    fn default() -> Self {
+        Self::new(&IndexConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::quantization::{StoredVector, VectorPrecision};
+
+    #[test]
+    fn test_flat_index() {
+        let index = FlatIndex::default();
+        for i in 0..10_000 {
+            index.insert(vec![i as f64, 0.0], i.to_string());
+        }
+        index.remove("5000".to_string());
+
+        let neighbors = index.search(&[5000.2, 0.0], 3, 0);
+        let ids: Vec<String> = neighbors.iter().map(|n| n.id.clone()).collect();
+        assert_eq!(ids, ["5001", "4999", "5002"]);
+        assert!((neighbors[0].distance - 0.8).abs() < 1e-3);
+
+        assert_eq!(index.search(&[0.0, 0.0], 0, 0).len(), 0);
+        assert_eq!(index.search(&[0.0, 0.0], 20_000, 0).len(), 9_999);
+    }
+
+    #[test]
+    fn test_flat_index_int8() {
+        let index = FlatIndex::new(&IndexConfig {
+            precision: VectorPrecision::Int8,
+            ..Default::default()
+        });
+        for i in 0..INT8_TRAINING_SIZE + 10 {
+            index.insert(vec![(i % 100) as f64, (i / 100) as f64], i.to_string());
+        }
+        assert!(!index.quantizer.read().unwrap().needs_training());
+        assert!(index
+            .vectors
+            .read()
+            .unwrap()
+            .iter()
+            .all(|(_, v)| matches!(v, StoredVector::Int8(_))));
+
+        let neighbors = index.search(&[42.0, 3.0], 1, 0);
+        assert_eq!(neighbors[0].id, "342");
+        assert!(neighbors[0].distance < 0.1);
+    }
+}
@@ -0,0 +1,644 @@
+use crate::index::db::{
+    live_positions, AnnNeighbor, DistanceMetric, IndexConfig, IvfPqConfig, SearchIndex,
+};
+use crate::index::flat::{compare_distance, top_k};
+use crate::index::kmeans::{assign, kmeans, nearest_centroid, normalize, squared_distance};
+use crate::index::quantization::{Quantizer, StoredVector, INT8_TRAINING_SIZE};
+use crate::index::rerank::FullVectors;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+
+const KMEANS_ITERATIONS: usize = 20;
+const KMEANS_SEED: u64 = 42;
+// codes are stored as u8
+const CODEBOOK_SIZE: usize = 256;
+// removed items are dropped once there are this many and they are at least
+// a quarter of all items, so compacting is amortized over the removals
+const MIN_REMOVED_BEFORE_COMPACTION: usize = 100;
+
+/// Inverted file index with product quantization (IVF-PQ).
+///
+/// Vectors are assigned to the nearest of `n_lists` coarse centroids and the
+/// residual to that centroid is split into `n_subvectors` parts, each stored
+/// as the one byte id of its nearest codebook entry. A search only scans the
+/// `n_probe` lists closest to the query and estimates distances from the
+/// codes. Both the centroids and the codebooks are learned with k-means from
+/// the first `training_size` vectors, until then the vectors are kept as is
+/// and searched exactly.
+#[derive(Clone)]
+pub struct IvfPqIndex {
+    pub config: IndexConfig,
+    state: Arc<RwLock<IvfPqState>>,
+    full_vectors: FullVectors,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct IvfPqState {
+    quantizer: Quantizer,
+    ids: Vec<String>,
+    // item index of every id which is not removed
+    #[serde(skip)]
+    positions: HashMap<String, usize>,
+    removed: HashSet<usize>,
+    // full vectors: all of them before training, afterwards only to re-rank
+    vectors: Vec<StoredVector>,
+    coarse: Vec<Vec<f64>>,
+    // dimension range of every subvector
+    subvectors: Vec<Range<usize>>,
+    // codebooks[subvector][code] is the part of the residual that `code` stands for
+    codebooks: Vec<Vec<Vec<f64>>>,
+    // item indexes by coarse centroid
+    lists: Vec<Vec<usize>>,
+    // n_subvectors codes per item
+    codes: Vec<u8>,
+    // coarse centroid of every item
+    assignments: Vec<u32>,
+}
+
+impl IvfPqState {
+    fn is_trained(&self) -> bool {
+        !self.coarse.is_empty()
+    }
+
+    fn live_items(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.ids.len()).filter(move |i| !self.removed.contains(i))
+    }
+
+    /// Removed items stay in their list, searches skip them until the next
+    /// compaction.
+    fn tombstone(&mut self, index: usize) {
+        self.removed.insert(index);
+    }
+
+    fn needs_compaction(&self) -> bool {
+        self.removed.len() >= MIN_REMOVED_BEFORE_COMPACTION
+            && self.removed.len() * 4 >= self.ids.len()
+    }
+
+    /// Drops the removed items and renumbers the others in the same order.
+    /// Unlike the rebuild of the HNSW graph nothing is learned again, this
+    /// only copies the items once.
+    fn compact(&mut self) {
+        let removed = std::mem::take(&mut self.removed);
+        self.ids = without_removed(std::mem::take(&mut self.ids), &removed);
+        self.vectors = without_removed(std::mem::take(&mut self.vectors), &removed);
+        self.assignments = without_removed(std::mem::take(&mut self.assignments), &removed);
+        let n_subvectors = self.subvectors.len().max(1);
+        let codes: Vec<&[u8]> = self.codes.chunks(n_subvectors).collect();
+        self.codes = without_removed(codes, &removed).concat();
+        self.lists = vec![Vec::new(); self.coarse.len()];
+        for (index, &list) in self.assignments.iter().enumerate() {
+            self.lists[list as usize].push(index);
+        }
+        self.positions = live_positions(&self.ids, &self.removed);
+    }
+
+    /// The stored vector if there is one, otherwise the approximation given
+    /// by the coarse centroid and the codes.
+    fn vector(&self, index: usize) -> Vec<f64> {
+        if let Some(v) = self.vectors.get(index) {
+            return self.quantizer.decode(v);
+        }
+        let mut v = self.coarse[self.assignments[index] as usize].clone();
+        let n_subvectors = self.subvectors.len();
+        let codes = &self.codes[index * n_subvectors..(index + 1) * n_subvectors];
+        for ((range, codebook), &code) in self.subvectors.iter().zip(&self.codebooks).zip(codes) {
+            for (value, part) in v[range.clone()].iter_mut().zip(&codebook[code as usize]) {
+                *value += part;
+            }
+        }
+        v
+    }
+}
+
+fn without_removed<T>(items: Vec<T>, removed: &HashSet<usize>) -> Vec<T> {
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !removed.contains(index))
+        .map(|(_, item)| item)
+        .collect()
+}
+
+fn split_dimensions(dimension: usize, n_subvectors: usize) -> Vec<Range<usize>> {
+    let n_subvectors = n_subvectors.clamp(1, dimension.max(1));
+    (0..n_subvectors)
+        .map(|i| i * dimension / n_subvectors..(i + 1) * dimension / n_subvectors)
+        .collect()
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a1, b1)| a1 * b1).sum()
+}
+
+impl IvfPqIndex {
+    pub fn new(config: &IndexConfig) -> Self {
+        IvfPqIndex {
+            config: config.clone(),
+            state: Arc::new(RwLock::new(IvfPqState {
+                quantizer: Quantizer::new(config.metric(), config.precision),
+                ids: Vec::new(),
+                positions: HashMap::new(),
+                removed: HashSet::new(),
+                vectors: Vec::new(),
+                coarse: Vec::new(),
+                subvectors: Vec::new(),
+                codebooks: Vec::new(),
+                lists: Vec::new(),
+                codes: Vec::new(),
+                assignments: Vec::new(),
+            })),
+            full_vectors: FullVectors::new(config),
+        }
+    }
+
+    fn pq_config(&self) -> &IvfPqConfig {
+        &self.config.ivf_pq
+    }
+
+    pub fn save<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        let state = self.state.read().map_err(|_| "RwLock Error")?;
+        bincode::serialize_into(writer, &(&self.config, &*state, &self.full_vectors))?;
+        Ok(())
+    }
+
+    pub fn load<R: Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        let (config, mut state, full_vectors): (IndexConfig, IvfPqState, FullVectors) =
+            bincode::deserialize_from(reader)?;
+        state.positions = live_positions(&state.ids, &state.removed);
+        Ok(IvfPqIndex {
+            config,
+            state: Arc::new(RwLock::new(state)),
+            full_vectors,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.read().unwrap().positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Cosine distance is computed as euclidean distance between normalized vectors.
+    fn prepare(&self, v: Vec<f64>) -> Vec<f64> {
+        match self.config.metric() {
+            DistanceMetric::Cosine => normalize(v),
+            _ => v,
+        }
+    }
+
+    fn train(&self, state: &mut IvfPqState) {
+        let vectors: Vec<Vec<f64>> = state
+            .vectors
+            .iter()
+            .map(|v| self.prepare(state.quantizer.decode(v)))
+            .collect();
+        let training: Vec<Vec<f64>> = state.live_items().map(|i| vectors[i].clone()).collect();
+        let dimension = training.first().map_or(0, |v| v.len());
+
+        state.coarse = kmeans(
+            &training,
+            self.pq_config().n_lists,
+            KMEANS_ITERATIONS,
+            KMEANS_SEED,
+        );
+        let residuals: Vec<Vec<f64>> = assign(&state.coarse, &training)
+            .into_iter()
+            .zip(&training)
+            .map(|(list, v)| {
+                v.iter()
+                    .zip(&state.coarse[list])
+                    .map(|(a, c)| a - c)
+                    .collect()
+            })
+            .collect();
+        state.subvectors = split_dimensions(dimension, self.pq_config().n_subvectors);
+        state.codebooks = state
+            .subvectors
+            .iter()
+            .map(|range| {
+                let parts: Vec<Vec<f64>> = residuals
+                    .iter()
+                    .map(|r| r[range.clone()].to_vec())
+                    .collect();
+                kmeans(&parts, CODEBOOK_SIZE, KMEANS_ITERATIONS, KMEANS_SEED)
+            })
+            .collect();
+
+        state.lists = vec![Vec::new(); state.coarse.len()];
+        state.codes = Vec::with_capacity(vectors.len() * state.subvectors.len());
+        state.assignments = Vec::with_capacity(vectors.len());
+        for (index, v) in vectors.iter().enumerate() {
+            Self::add_codes(state, index, v);
+        }
+        if !self.pq_config().rerank {
+            state.vectors = Vec::new();
+        }
+    }
+
+    fn add_codes(state: &mut IvfPqState, index: usize, v: &[f64]) {
+        let list = nearest_centroid(&state.coarse, v);
+        let residual: Vec<f64> = v
+            .iter()
+            .zip(&state.coarse[list])
+            .map(|(a, c)| a - c)
+            .collect();
+        for (range, codebook) in state.subvectors.iter().zip(&state.codebooks) {
+            state
+                .codes
+                .push(nearest_centroid(codebook, &residual[range.clone()]) as u8);
+        }
+        state.assignments.push(list as u32);
+        if !state.removed.contains(&index) {
+            state.lists[list].push(index);
+        }
+    }
+
+    /// Distances of every item before training. Afterwards the estimated
+    /// distances of the probed lists, the `n_rerank` closest ones replaced by
+    /// their exact distance when `rerank` is set.
+    fn candidates(&self, state: &IvfPqState, v: &[f64], n_rerank: usize) -> Vec<(f64, usize)> {
+        let query = state.quantizer.encode_query(v);
+        let exact = |index: usize| {
+            let distance = state
+                .quantizer
+                .float_distance(&query, &state.vectors[index]);
+            (distance, index)
+        };
+        if !state.is_trained() {
+            return state.live_items().map(exact).collect();
+        }
+        let estimated = self.scan_lists(state, &self.prepare(v.to_vec()));
+        if self.pq_config().rerank {
+            top_k(estimated, n_rerank)
+                .into_iter()
+                .map(|(_, index)| exact(index))
+                .collect()
+        } else {
+            estimated
+        }
+    }
+
+    /// Sorts the candidates and looks up their ids.
+    fn neighbors(&self, state: &IvfPqState, mut candidates: Vec<(f64, usize)>) -> Vec<AnnNeighbor> {
+        let metric = self.config.metric();
+        candidates.sort_by(compare_distance);
+        candidates
+            .into_iter()
+            .map(|(distance, index)| AnnNeighbor {
+                id: state.ids[index].clone(),
+                index,
+                distance,
+                similarity: metric.similarity(distance),
+            })
+            .collect()
+    }
+
+    /// Estimated distances of the items in the `n_probe` lists closest to `query`.
+    fn scan_lists(&self, state: &IvfPqState, query: &[f64]) -> Vec<(f64, usize)> {
+        let metric = self.config.metric();
+        let mut lists: Vec<(f64, usize)> = state
+            .coarse
+            .iter()
+            .enumerate()
+            .map(|(list, centroid)| match metric {
+                DistanceMetric::InnerProduct => (-dot(query, centroid), list),
+                _ => (squared_distance(query, centroid), list),
+            })
+            .collect();
+        lists.sort_by(compare_distance);
+        lists.truncate(self.pq_config().n_probe.max(1));
+
+        let n_subvectors = state.subvectors.len();
+        let mut distances = Vec::new();
+        for (_, list) in lists {
+            let centroid = &state.coarse[list];
+            let residual: Vec<f64> = query.iter().zip(centroid).map(|(q, c)| q - c).collect();
+            // distance (or product) of the query part to every codebook entry
+            let tables: Vec<Vec<f64>> = state
+                .subvectors
+                .iter()
+                .zip(&state.codebooks)
+                .map(|(range, codebook)| {
+                    codebook
+                        .iter()
+                        .map(|entry| match metric {
+                            DistanceMetric::InnerProduct => dot(&query[range.clone()], entry),
+                            _ => squared_distance(&residual[range.clone()], entry),
+                        })
+                        .collect()
+                })
+                .collect();
+            let base = match metric {
+                DistanceMetric::InnerProduct => dot(query, centroid),
+                _ => 0.0,
+            };
+
+            for &index in &state.lists[list] {
+                if state.removed.contains(&index) {
+                    continue;
+                }
+                let codes = &state.codes[index * n_subvectors..(index + 1) * n_subvectors];
+                let estimate: f64 = codes
+                    .iter()
+                    .zip(&tables)
+                    .map(|(&code, table)| table[code as usize])
+                    .sum();
+                let distance = match metric {
+                    DistanceMetric::Euclidean => estimate.sqrt(),
+                    // squared distance of unit vectors is 2 * cosine distance
+                    DistanceMetric::Cosine => estimate / 2.0,
+                    DistanceMetric::InnerProduct => -(base + estimate),
+                    // squared distance of bits is the number of different bits
+                    DistanceMetric::Hamming => estimate,
+                };
+                distances.push((distance, index));
+            }
+        }
+        distances
+    }
+}
+
+impl SearchIndex for IvfPqIndex {
+    /// Adding an id which is already in the index replaces its vector.
+    fn insert(&self, v: Vec<f64>, id: String) {
+        let mut state = self.state.write().unwrap();
+        let index = state.ids.len();
+        if let Some(old) = state.positions.insert(id.clone(), index) {
+            state.tombstone(old);
+        }
+        self.full_vectors.insert(&id, &v);
+        state.ids.push(id);
+        if !state.is_trained() || self.pq_config().rerank {
+            let stored = state.quantizer.encode(&v);
+            state.vectors.push(stored);
+            if state.quantizer.needs_training() && state.vectors.len() >= INT8_TRAINING_SIZE {
+                let IvfPqState {
+                    quantizer, vectors, ..
+                } = &mut *state;
+                quantizer.train_and_encode(vectors.iter_mut());
+            }
+        }
+
+        if state.is_trained() {
+            Self::add_codes(&mut state, index, &self.prepare(v));
+        } else if state.positions.len() >= self.pq_config().training_size {
+            self.train(&mut state);
+        }
+        if state.needs_compaction() {
+            state.compact();
+        }
+    }
+
+    /// Before training this is an exact scan. Afterwards up to `ef` candidates
+    /// are taken from the probed lists, they are re-ranked with their exact
+    /// distance when `rerank` is set.
+    fn search(&self, v: &[f64], k: usize, ef: usize) -> Vec<AnnNeighbor> {
+        let state = self.state.read().unwrap();
+        let neighbors = top_k(self.candidates(&state, v, ef.max(k)), k);
+        self.neighbors(&state, neighbors)
+    }
+
+    /// Keeps every candidate of the probed lists within `radius`, all of them
+    /// are re-ranked when `rerank` is set. The cost does not depend on
+    /// `max_results`.
+    fn search_range(
+        &self,
+        v: &[f64],
+        radius: f64,
+        max_results: usize,
+        _ef: usize,
+        accept: &(dyn Fn(&str) -> bool + Sync),
+    ) -> Vec<AnnNeighbor> {
+        let state = self.state.read().unwrap();
+        let mut candidates = self.candidates(&state, v, usize::MAX);
+        candidates.retain(|&(distance, index)| distance <= radius && accept(&state.ids[index]));
+        let neighbors = top_k(candidates, max_results);
+        self.neighbors(&state, neighbors)
+    }
+
+    fn remove(&self, id: String) -> bool {
+        let mut state = self.state.write().unwrap();
+        match state.positions.remove(&id) {
+            Some(index) => {
+                state.tombstone(index);
+                self.full_vectors.remove(&id);
+                if state.needs_compaction() {
+                    state.compact();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.state.read().unwrap().positions.contains_key(id)
+    }
+
+    fn ids(&self) -> Vec<String> {
+        self.state
+            .read()
+            .unwrap()
+            .positions
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Without `rerank` vectors are only approximated from their codes after
+    /// training (normalized for cosine distance).
+    fn vector(&self, id: &str) -> Option<Vec<f64>> {
+        let state = self.state.read().unwrap();
+        let index = *state.positions.get(id)?;
+        Some(state.vector(index))
+    }
+
+    fn full_vector(&self, id: &str) -> Option<Vec<f64>> {
+        self.full_vectors.get(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::db::IndexBackend;
+    use crate::index::flat::FlatIndex;
+    use crate::index::test_utils::random_vectors;
+
+    fn config(metric: DistanceMetric, rerank: bool) -> IndexConfig {
+        IndexConfig {
+            backend: IndexBackend::IvfPq,
+            metric: Some(metric),
+            ivf_pq: IvfPqConfig {
+                n_lists: 8,
+                n_subvectors: 4,
+                n_probe: 4,
+                training_size: 400,
+                rerank,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn recall(index: &IvfPqIndex, flat: &FlatIndex, queries: &[Vec<f64>]) -> f64 {
+        let mut found = 0;
+        for query in queries {
+            let expected: HashSet<String> = flat
+                .search(query, 10, 0)
+                .into_iter()
+                .map(|n| n.id)
+                .collect();
+            found += index
+                .search(query, 10, 50)
+                .into_iter()
+                .filter(|n| expected.contains(&n.id))
+                .count();
+        }
+        found as f64 / (10 * queries.len()) as f64
+    }
+
+    #[test]
+    fn test_ivf_pq() {
+        let vectors = random_vectors(600, 16, 1);
+        let queries = random_vectors(20, 16, 2);
+        for &metric in &[
+            DistanceMetric::Euclidean,
+            DistanceMetric::Cosine,
+            DistanceMetric::InnerProduct,
+        ] {
+            let flat = FlatIndex::new(&config(metric, false));
+            let index = IvfPqIndex::new(&config(metric, false));
+            let reranked = IvfPqIndex::new(&config(metric, true));
+            for (i, v) in vectors.iter().enumerate() {
+                flat.insert(v.clone(), i.to_string());
+                index.insert(v.clone(), i.to_string());
+                reranked.insert(v.clone(), i.to_string());
+            }
+            assert!(index.state.read().unwrap().is_trained());
+            assert!(index.state.read().unwrap().vectors.is_empty());
+            assert_eq!(reranked.state.read().unwrap().vectors.len(), 600);
+
+            let approximate = recall(&index, &flat, &queries);
+            let exact = recall(&reranked, &flat, &queries);
+            assert!(approximate > 0.5, "{:?} recall {}", metric, approximate);
+            assert!(exact >= approximate, "{:?} recall {}", metric, exact);
+        }
+    }
+
+    #[test]
+    fn test_ivf_pq_search_range() {
+        let vectors = random_vectors(600, 16, 1);
+        let queries = random_vectors(5, 16, 2);
+        let flat = FlatIndex::new(&config(DistanceMetric::Euclidean, false));
+        // probing every list and re-ranking finds exactly the neighbors in range
+        let mut exact_config = config(DistanceMetric::Euclidean, true);
+        exact_config.ivf_pq.n_probe = exact_config.ivf_pq.n_lists;
+        let index = IvfPqIndex::new(&exact_config);
+        for (i, v) in vectors.iter().enumerate() {
+            flat.insert(v.clone(), i.to_string());
+            index.insert(v.clone(), i.to_string());
+        }
+        assert!(index.state.read().unwrap().is_trained());
+
+        let all = |_: &str| true;
+        for query in &queries {
+            let radius = flat.search(query, 50, 0)[49].distance;
+            let ids = |neighbors: Vec<AnnNeighbor>| -> Vec<String> {
+                neighbors.into_iter().map(|n| n.id).collect()
+            };
+            let expected = ids(flat.search_range(query, radius, usize::MAX, 0, &all));
+            assert_eq!(expected.len(), 50);
+            assert_eq!(
+                ids(index.search_range(query, radius, usize::MAX, 0, &all)),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_ivf_pq_remove_save_load() {
+        let index = IvfPqIndex::new(&config(DistanceMetric::Euclidean, true));
+        for (i, v) in random_vectors(500, 8, 1).into_iter().enumerate() {
+            index.insert(v, i.to_string());
+        }
+        let query = random_vectors(1, 8, 2).remove(0);
+        let top = index.search(&query, 1, 50).remove(0).id;
+        index.remove(top.clone());
+        let neighbors = index.search(&query, 500, 500);
+        assert!(neighbors.iter().all(|n| n.id != top));
+
+        let mut buffer = Vec::new();
+        index.save(&mut buffer).unwrap();
+        let loaded = IvfPqIndex::load(buffer.as_slice()).unwrap();
+        let ids = |neighbors: Vec<AnnNeighbor>| -> Vec<String> {
+            neighbors.into_iter().map(|n| n.id).collect()
+        };
+        assert_eq!(
+            ids(loaded.search(&query, 10, 50)),
+            ids(index.search(&query, 10, 50))
+        );
+    }
+
+    #[test]
+    fn test_ivf_pq_vector() {
+        let vectors = random_vectors(500, 8, 1);
+        let index = IvfPqIndex::new(&config(DistanceMetric::Euclidean, false));
+        let reranked = IvfPqIndex::new(&config(DistanceMetric::Euclidean, true));
+        for (i, v) in vectors.iter().enumerate() {
+            index.insert(v.clone(), i.to_string());
+            reranked.insert(v.clone(), i.to_string());
+        }
+        // without re-ranking only an approximation of the vector is kept
+        let approximated = index.vector("7").unwrap();
+        assert!(squared_distance(&approximated, &vectors[7]) < 0.05);
+        assert!(squared_distance(&reranked.vector("7").unwrap(), &vectors[7]) < 1e-9);
+
+        index.remove("7".to_string());
+        assert!(index.vector("7").is_none());
+    }
+
+    #[test]
+    fn test_ivf_pq_compaction() {
+        for rerank in [false, true] {
+            let index = IvfPqIndex::new(&config(DistanceMetric::Euclidean, rerank));
+            // every image is added and then replaced twice
+            for seed in 1..=3 {
+                for (i, v) in random_vectors(500, 8, seed).into_iter().enumerate() {
+                    index.insert(v, i.to_string());
+                }
+            }
+            for i in 0..50 {
+                index.remove(i.to_string());
+            }
+            let state = index.state.read().unwrap();
+            assert_eq!(state.positions.len(), 450);
+            // the replaced vectors are freed
+            assert!(state.ids.len() < 2 * 450);
+            assert!(state.removed.len() * 4 < state.ids.len());
+            assert_eq!(state.assignments.len(), state.ids.len());
+            assert_eq!(state.codes.len(), state.ids.len() * 4);
+            let n_listed: usize = state.lists.iter().map(|list| list.len()).sum();
+            assert_eq!(n_listed, state.ids.len());
+            assert_eq!(
+                state.vectors.len(),
+                if rerank { state.ids.len() } else { 0 }
+            );
+            drop(state);
+
+            let last = random_vectors(500, 8, 3);
+            assert!(index.vector("10").is_none());
+            let neighbors = index.search(&last[100], 10, 50);
+            assert!(neighbors.iter().any(|n| n.id == "100"));
+            if rerank {
+                assert!(squared_distance(&index.vector("100").unwrap(), &last[100]) < 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use rand_core::{RngCore, SeedableRng};
+use rand_pcg::Pcg64;
+use std::thread;
+
+// below this many vectors per thread spawning threads costs more than it saves
+const MIN_VECTORS_PER_THREAD: usize = 1024;
+
+pub fn squared_distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a1, b1)| (a1 - b1).powi(2)).sum()
+}
+
+/// Scales `v` to unit length, so that squared euclidean distances order
+/// vectors like cosine distance. Zero vectors are left as they are.
+pub fn normalize(mut v: Vec<f64>) -> Vec<f64> {
+    let norm = v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+    v
+}
+
+/// Index of the centroid closest to `v` in squared euclidean distance.
+pub fn nearest_centroid(centroids: &[Vec<f64>], v: &[f64]) -> usize {
+    centroids
+        .iter()
+        .map(|c| squared_distance(c, v))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0, |(i, _)| i)
+}
+
+/// Assigns every vector to its nearest centroid, in parallel for large inputs.
+pub fn assign(centroids: &[Vec<f64>], vectors: &[Vec<f64>]) -> Vec<usize> {
+    let n_threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(vectors.len() / MIN_VECTORS_PER_THREAD)
+        .max(1);
+    let chunk_size = (vectors.len() / n_threads).max(1);
+    thread::scope(|scope| {
+        let handles: Vec<_> = vectors
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|v| nearest_centroid(centroids, v))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+fn uniform(rng: &mut Pcg64) -> f64 {
+    (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// k-means++ initialization: every next centroid is picked with a
+/// probability proportional to its squared distance to the closest centroid
+/// so far, which spreads the initial centroids over the data.
+fn init_centroids(vectors: &[Vec<f64>], k: usize, rng: &mut Pcg64) -> Vec<Vec<f64>> {
+    let first = (rng.next_u64() as usize) % vectors.len();
+    let mut centroids = vec![vectors[first].clone()];
+    let mut distances: Vec<f64> = vectors
+        .iter()
+        .map(|v| squared_distance(v, &centroids[0]))
+        .collect();
+    while centroids.len() < k {
+        let total: f64 = distances.iter().sum();
+        let next = if total > 0.0 {
+            let mut target = uniform(rng) * total;
+            distances
+                .iter()
+                .position(|&d| {
+                    target -= d;
+                    target < 0.0
+                })
+                .unwrap_or(vectors.len() - 1)
+        } else {
+            (rng.next_u64() as usize) % vectors.len()
+        };
+        let centroid = vectors[next].clone();
+        for (distance, v) in distances.iter_mut().zip(vectors) {
+            *distance = distance.min(squared_distance(v, &centroid));
+        }
+        centroids.push(centroid);
+    }
+    centroids
+}
+
+/// Lloyd's k-means with squared euclidean distance. Returns at most `k`
+/// centroids (fewer if there are fewer vectors). The result only depends on
+/// `seed` and the input.
+pub fn kmeans(vectors: &[Vec<f64>], k: usize, n_iterations: usize, seed: u64) -> Vec<Vec<f64>> {
+    let k = k.min(vectors.len());
+    if k == 0 {
+        return vec![];
+    }
+    let dimension = vectors[0].len();
+    let mut rng = Pcg64::seed_from_u64(seed);
+
+    let mut centroids = init_centroids(vectors, k, &mut rng);
+
+    let mut assignments = vec![usize::MAX; vectors.len()];
+    for _ in 0..n_iterations {
+        let new_assignments = assign(&centroids, vectors);
+        if new_assignments == assignments {
+            break;
+        }
+        assignments = new_assignments;
+
+        let mut sums = vec![vec![0.0; dimension]; k];
+        let mut counts = vec![0usize; k];
+        for (v, &cluster) in vectors.iter().zip(&assignments) {
+            counts[cluster] += 1;
+            for (sum, value) in sums[cluster].iter_mut().zip(v) {
+                *sum += value;
+            }
+        }
+        for (cluster, (sum, count)) in sums.into_iter().zip(counts).enumerate() {
+            centroids[cluster] = if count > 0 {
+                sum.into_iter().map(|s| s / count as f64).collect()
+            } else {
+                // restart empty clusters from a random vector
+                vectors[(rng.next_u64() as usize) % vectors.len()].clone()
+            };
+        }
+    }
+    centroids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kmeans() {
+        let centers = [[0.0, 0.0], [10.0, 10.0], [-10.0, 10.0]];
+        let mut vectors = Vec::new();
+        for i in 0..90 {
+            let center = centers[i % 3];
+            let offset = (i / 3) as f64 / 30.0 - 0.5;
+            vectors.push(vec![center[0] + offset, center[1] - offset]);
+        }
+
+        let centroids = kmeans(&vectors, 3, 20, 42);
+        assert_eq!(centroids.len(), 3);
+        for center in &centers {
+            let nearest = &centroids[nearest_centroid(&centroids, center)];
+            assert!(squared_distance(nearest, center) < 0.1);
+        }
+
+        assert_eq!(kmeans(&vectors[..2], 3, 20, 42).len(), 2);
+        assert!(kmeans(&[], 3, 20, 42).is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// JSON attributes of an image, e.g. `{"category": "shoes", "price": 80}`.
+pub type Metadata = serde_json::Map<String, Value>;
+
+/// Serializes JSON values as a string in binary formats (the write-ahead log
+/// and snapshots), which cannot represent values of an unknown type. Human
+/// readable formats like the JSON API see the plain value.
+pub mod json_string {
+    use serde::de::DeserializeOwned;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            value.serialize(serializer)
+        } else {
+            let json = serde_json::to_string(value).map_err(serde::ser::Error::custom)?;
+            json.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        if deserializer.is_human_readable() {
+            T::deserialize(deserializer)
+        } else {
+            let json = String::deserialize(deserializer)?;
+            serde_json::from_str(&json).map_err(de::Error::custom)
+        }
+    }
+}
+
+/// Condition on the metadata of an image. Fields can address nested objects
+/// with dots, e.g. `"brand.name"`. Comparisons are false for images without
+/// the field.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Exists {
+        field: String,
+    },
+    Eq {
+        field: String,
+        #[serde(with = "json_string")]
+        #[schemars(with = "Value")]
+        value: Value,
+    },
+    In {
+        field: String,
+        #[serde(with = "json_string")]
+        #[schemars(with = "Vec<Value>")]
+        values: Vec<Value>,
+    },
+    Lt {
+        field: String,
+        value: f64,
+    },
+    Lte {
+        field: String,
+        value: f64,
+    },
+    Gt {
+        field: String,
+        value: f64,
+    },
+    Gte {
+        field: String,
+        value: f64,
+    },
+}
+
+fn get<'a>(metadata: &'a Metadata, field: &str) -> Option<&'a Value> {
+    let mut parts = field.split('.');
+    let mut value = metadata.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_object()?.get(part)?;
+    }
+    Some(value)
+}
+
+// numbers are compared by value so that 100 equals 100.0
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        let number = |field: &str| get(metadata, field).and_then(Value::as_f64);
+        match self {
+            Filter::And(filters) => filters.iter().all(|f| f.matches(metadata)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(metadata)),
+            Filter::Not(filter) => !filter.matches(metadata),
+            Filter::Exists { field } => get(metadata, field).is_some(),
+            Filter::Eq { field, value } => {
+                get(metadata, field).is_some_and(|v| values_equal(v, value))
+            }
+            Filter::In { field, values } => get(metadata, field)
+                .is_some_and(|v| values.iter().any(|value| values_equal(v, value))),
+            Filter::Lt { field, value } => number(field).is_some_and(|v| v < *value),
+            Filter::Lte { field, value } => number(field).is_some_and(|v| v <= *value),
+            Filter::Gt { field, value } => number(field).is_some_and(|v| v > *value),
+            Filter::Gte { field, value } => number(field).is_some_and(|v| v >= *value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::events::{AddImage, ImageBytes, ImageSource};
+    use serde_json::json;
+
+    fn metadata(value: Value) -> Metadata {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter: Filter = serde_json::from_value(json!({"and": [
+            {"eq": {"field": "category", "value": "shoes"}},
+            {"lt": {"field": "price", "value": 100}},
+            {"not": {"eq": {"field": "brand.name", "value": "acme"}}}
+        ]}))
+        .unwrap();
+
+        let shoes = metadata(json!({"category": "shoes", "price": 80, "brand": {"name": "other"}}));
+        assert!(filter.matches(&shoes));
+        let expensive = metadata(json!({"category": "shoes", "price": 120.5}));
+        assert!(!filter.matches(&expensive));
+        let acme = metadata(json!({"category": "shoes", "price": 80, "brand": {"name": "acme"}}));
+        assert!(!filter.matches(&acme));
+        assert!(!filter.matches(&Metadata::new()));
+
+        let price_in: Filter =
+            serde_json::from_value(json!({"in": {"field": "price", "values": [80.0, 90]}}))
+                .unwrap();
+        assert!(price_in.matches(&shoes));
+        assert!(Filter::Exists {
+            field: "brand".into()
+        }
+        .matches(&shoes));
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let filter = Filter::In {
+            field: "category".into(),
+            values: vec![json!("shoes"), json!({"nested": [1, 2]})],
+        };
+        let bytes = bincode::serialize(&filter).unwrap();
+        assert_eq!(bincode::deserialize::<Filter>(&bytes).unwrap(), filter);
+
+        let add_image = AddImage {
+            source: ImageSource::ImageBytes(ImageBytes { bytes: vec![1, 2] }),
+            collection_name: "images".into(),
+            id: "shoe".into(),
+            metadata: metadata(json!({"category": "shoes", "price": 80})),
+        };
+        let bytes = bincode::serialize(&add_image).unwrap();
+        assert_eq!(bincode::deserialize::<AddImage>(&bytes).unwrap(), add_image);
+    }
+}
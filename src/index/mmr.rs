@@ -0,0 +1,97 @@
+use crate::index::db::DistanceMetric;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+fn default_lambda() -> f64 {
+    0.5
+}
+
+/// Diversifies results with maximal marginal relevance: every next result
+/// maximizes `lambda * similarity to the query - (1 - lambda) * highest
+/// similarity to the results picked so far`. `lambda` of 1 keeps the ranking
+/// by relevance, 0 only looks at diversity.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Mmr {
+    #[serde(default = "default_lambda")]
+    pub lambda: f64,
+    // nearest neighbors to choose the results from, 4 times the number of results by default
+    #[serde(default)]
+    pub n_candidates: Option<usize>,
+}
+
+impl Mmr {
+    pub fn n_candidates(&self, n_results: usize) -> usize {
+        self.n_candidates.unwrap_or(4 * n_results).max(n_results)
+    }
+
+    /// Picks `k` of the candidates given their similarity to the query and
+    /// their vectors, returns their positions in the order they were picked.
+    pub fn select(
+        &self,
+        metric: DistanceMetric,
+        relevance: &[f64],
+        vectors: &[Vec<f64>],
+        k: usize,
+    ) -> Vec<usize> {
+        let mut selected: Vec<usize> = Vec::with_capacity(k.min(relevance.len()));
+        // highest similarity of every candidate to the selected ones
+        let mut redundancy = vec![0.0f64; relevance.len()];
+        let mut remaining: Vec<usize> = (0..relevance.len()).collect();
+        while selected.len() < k && !remaining.is_empty() {
+            let score = |i: usize| self.lambda * relevance[i] - (1.0 - self.lambda) * redundancy[i];
+            let (position, &best) = remaining
+                .iter()
+                .enumerate()
+                .max_by(|(_, &a), (_, &b)| score(a).total_cmp(&score(b)).then(b.cmp(&a)))
+                .unwrap();
+            remaining.swap_remove(position);
+            selected.push(best);
+            for &i in &remaining {
+                let similarity =
+                    metric.similarity(metric.float_distance(&vectors[i], &vectors[best]));
+                redundancy[i] = redundancy[i].max(similarity);
+            }
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mmr() {
+        let metric = DistanceMetric::Euclidean;
+        let query = [0.0, 0.0];
+        // three copies of the same image and two different ones a bit further away
+        let vectors = vec![
+            vec![1.0, 0.0],
+            vec![1.0, 0.01],
+            vec![1.0, -0.01],
+            vec![0.0, 1.5],
+            vec![-1.5, 0.0],
+        ];
+        let relevance: Vec<f64> = vectors
+            .iter()
+            .map(|v| metric.similarity(metric.float_distance(&query, v)))
+            .collect();
+
+        let relevant = Mmr {
+            lambda: 1.0,
+            n_candidates: None,
+        };
+        assert_eq!(relevant.select(metric, &relevance, &vectors, 3), [0, 1, 2]);
+
+        let diverse = Mmr {
+            lambda: 0.5,
+            n_candidates: None,
+        };
+        let selected = diverse.select(metric, &relevance, &vectors, 3);
+        assert_eq!(selected[0], 0);
+        assert_eq!(selected[1..].iter().filter(|&&i| i >= 3).count(), 2);
+
+        assert_eq!(diverse.select(metric, &relevance, &vectors, 10).len(), 5);
+        assert_eq!(diverse.n_candidates(10), 40);
+    }
+}
@@ -1,2 +1,15 @@
+pub mod clusters;
 pub mod db;
+pub mod duplicates;
 pub mod events;
+pub mod flat;
+pub mod ivf_pq;
+pub mod kmeans;
+pub mod metadata;
+pub mod mmr;
+pub mod pca;
+pub mod quantization;
+pub mod rerank;
+#[cfg(test)]
+mod test_utils;
+pub mod wal;
@@ -0,0 +1,222 @@
+use crate::state::parallel::parallel_map;
+use rand_core::{RngCore, SeedableRng};
+use rand_pcg::Pcg64;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// extra vectors iterated along with the components, speeds up convergence
+const OVERSAMPLING: usize = 8;
+const MAX_ITERATIONS: usize = 100;
+const TOLERANCE: f64 = 1e-9;
+// keeps whitening from blowing up directions without variance
+const MIN_VARIANCE: f64 = 1e-6;
+
+fn default_whiten() -> bool {
+    true
+}
+
+fn default_n_training() -> usize {
+    1000
+}
+
+/// Projects vectors on their `dimension` principal components before they
+/// are indexed or searched. The projection is fitted on the first
+/// `n_training` images (at least `dimension`) or on an explicit sample.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PcaConfig {
+    pub dimension: usize,
+    // scale every component to unit variance
+    #[serde(default = "default_whiten")]
+    pub whiten: bool,
+    #[serde(default = "default_n_training")]
+    pub n_training: usize,
+}
+
+impl PcaConfig {
+    /// Number of images after which the projection is fitted.
+    pub fn n_training(&self) -> usize {
+        self.n_training.max(self.dimension)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pca {
+    pub mean: Vec<f64>,
+    // principal components by decreasing variance, scaled when whitening
+    pub components: Vec<Vec<f64>>,
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a1, b1)| a1 * b1).sum()
+}
+
+/// Modified Gram-Schmidt, vectors which are linear combinations of the
+/// previous ones become zero.
+fn orthonormalize(vectors: &mut [Vec<f64>]) {
+    for i in 0..vectors.len() {
+        let (previous, rest) = vectors.split_at_mut(i);
+        let v = &mut rest[0];
+        // twice for numerical stability
+        for _ in 0..2 {
+            for p in previous.iter() {
+                let projection = dot(v, p);
+                v.iter_mut().zip(p).for_each(|(x, y)| *x -= projection * y);
+            }
+        }
+        let norm = dot(v, v).sqrt();
+        if norm > 1e-12 {
+            v.iter_mut().for_each(|x| *x /= norm);
+        } else {
+            v.iter_mut().for_each(|x| *x = 0.0);
+        }
+    }
+}
+
+impl Pca {
+    /// Finds the principal components of `vectors` by orthogonal iteration on
+    /// their covariance matrix. The result only depends on the input.
+    pub fn fit(vectors: &[Vec<f64>], config: &PcaConfig) -> Pca {
+        let n = vectors.len().max(1) as f64;
+        let input_dimension = vectors.first().map_or(0, |v| v.len());
+        let mut mean = vec![0.0; input_dimension];
+        for v in vectors {
+            mean.iter_mut().zip(v).for_each(|(m, x)| *m += x / n);
+        }
+        let centered: Vec<Vec<f64>> = vectors
+            .iter()
+            .map(|v| v.iter().zip(&mean).map(|(x, m)| x - m).collect())
+            .collect();
+        let covariance: Vec<Vec<f64>> = parallel_map((0..input_dimension).collect(), |i| {
+            let mut row = vec![0.0; input_dimension];
+            for v in &centered {
+                row.iter_mut().zip(v).for_each(|(r, x)| *r += v[i] * x / n);
+            }
+            row
+        });
+        let multiply = |vectors: Vec<Vec<f64>>| -> Vec<Vec<f64>> {
+            parallel_map(vectors, |v| {
+                covariance.iter().map(|row| dot(row, &v)).collect()
+            })
+        };
+
+        let dimension = config.dimension.min(input_dimension);
+        let n_vectors = (dimension + OVERSAMPLING).min(input_dimension);
+        let mut rng = Pcg64::seed_from_u64(0);
+        let mut basis: Vec<Vec<f64>> = (0..n_vectors)
+            .map(|_| {
+                (0..input_dimension)
+                    .map(|_| (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64 - 0.5)
+                    .collect()
+            })
+            .collect();
+        orthonormalize(&mut basis);
+        for _ in 0..MAX_ITERATIONS {
+            let mut next = multiply(basis.clone());
+            orthonormalize(&mut next);
+            let change = next[..dimension]
+                .iter()
+                .zip(&basis)
+                .map(|(a, b)| 1.0 - dot(a, b).abs())
+                .fold(0.0, f64::max);
+            basis = next;
+            if change < TOLERANCE {
+                break;
+            }
+        }
+
+        // sort by the variance along every vector
+        let variances: Vec<f64> = basis
+            .iter()
+            .zip(multiply(basis.clone()))
+            .map(|(v, cv)| dot(v, &cv))
+            .collect();
+        let mut order: Vec<usize> = (0..basis.len()).collect();
+        order.sort_by(|&a, &b| variances[b].total_cmp(&variances[a]));
+        let max_variance = variances.iter().cloned().fold(0.0, f64::max);
+        let components = order
+            .into_iter()
+            .take(dimension)
+            .map(|i| {
+                let scale = if config.whiten {
+                    1.0 / (variances[i].max(0.0) + MIN_VARIANCE * max_variance).sqrt()
+                } else {
+                    1.0
+                };
+                basis[i].iter().map(|x| x * scale).collect()
+            })
+            .collect();
+        Pca { mean, components }
+    }
+
+    pub fn project(&self, v: &[f64]) -> Vec<f64> {
+        let centered: Vec<f64> = v.iter().zip(&self.mean).map(|(x, m)| x - m).collect();
+        self.components
+            .iter()
+            .map(|component| dot(component, &centered))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pca() {
+        // points around (1, 2, 3) spread mostly along (1, 1, 0), less along
+        // (0, 0, 1) and a little along (1, -1, 0)
+        let mut vectors = Vec::new();
+        for i in 0..200 {
+            let (a, b, c) = (
+                (i % 10) as f64 - 4.5,
+                ((i / 10) % 5) as f64 / 2.0 - 1.0,
+                ((i / 50) as f64 - 1.5) / 20.0,
+            );
+            vectors.push(vec![1.0 + a + c, 2.0 + a - c, 3.0 + b]);
+        }
+        let config = PcaConfig {
+            dimension: 2,
+            whiten: false,
+            n_training: 100,
+        };
+        let pca = Pca::fit(&vectors, &config);
+        assert_eq!(pca.components.len(), 2);
+        let expected = [[0.5f64.sqrt(), 0.5f64.sqrt(), 0.0], [0.0, 0.0, 1.0]];
+        for (component, expected) in pca.components.iter().zip(&expected) {
+            assert!((dot(component, expected).abs() - 1.0).abs() < 1e-6);
+        }
+        assert!(pca.project(&[1.0, 2.0, 3.0]).iter().all(|x| x.abs() < 1e-9));
+
+        // whitened components have unit variance and are uncorrelated
+        let whitened = Pca::fit(
+            &vectors,
+            &PcaConfig {
+                whiten: true,
+                ..config
+            },
+        );
+        let projected: Vec<Vec<f64>> = vectors.iter().map(|v| whitened.project(v)).collect();
+        let n = projected.len() as f64;
+        for i in 0..2 {
+            for j in 0..2 {
+                let covariance: f64 = projected.iter().map(|p| p[i] * p[j]).sum::<f64>() / n;
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((covariance - expected).abs() < 1e-3);
+            }
+        }
+
+        assert_eq!(config.n_training(), 100);
+        assert_eq!(
+            Pca::fit(
+                &vectors,
+                &PcaConfig {
+                    dimension: 5,
+                    ..config
+                }
+            )
+            .components
+            .len(),
+            3
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::index::db::{to_ordered_bits, DistanceMetric};
+use half::f16;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use space::Metric;
+use std::slice;
+
+/// Number of vectors an int8 collection stores in f32 before the
+/// per-dimension scales are learned and everything is converted to int8.
+pub const INT8_TRAINING_SIZE: usize = 1000;
+
+/// Precision used to store the vectors of a collection. Lower precision
+/// takes less memory (f32: 4 bytes, f16: 2 bytes, int8: 1 byte per dimension)
+/// at the cost of slightly less accurate distances. The Hamming metric
+/// ignores it and always stores one bit per dimension.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum VectorPrecision {
+    #[default]
+    F32,
+    F16,
+    Int8,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum StoredVector {
+    F32(Vec<f32>),
+    F16(Vec<f16>),
+    // value = stored value * scale of the dimension
+    Int8(Vec<i8>),
+    // one bit per dimension packed in words, set if the value is above 0.5,
+    // and the number of dimensions
+    Bits(Vec<u64>, usize),
+}
+
+/// Converts vectors between f64 and the storage precision of a collection and
+/// computes distances directly on stored vectors.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Quantizer {
+    pub metric: DistanceMetric,
+    pub precision: VectorPrecision,
+    // per-dimension scales for int8, learned from the first vectors
+    scales: Option<Vec<f64>>,
+}
+
+enum Values<'a> {
+    F32(slice::Iter<'a, f32>),
+    F16(slice::Iter<'a, f16>),
+    Int8(slice::Iter<'a, i8>, slice::Iter<'a, f64>),
+    Bits(&'a [u64], std::ops::Range<usize>),
+}
+
+impl<'a> Iterator for Values<'a> {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        match self {
+            Values::F32(values) => values.next().map(|&v| v as f64),
+            Values::F16(values) => values.next().map(|v| v.to_f64()),
+            Values::Int8(values, scales) => Some(*values.next()? as f64 * scales.next()?),
+            Values::Bits(words, bits) => {
+                let bit = bits.next()?;
+                Some((words[bit / 64] >> (bit % 64) & 1) as f64)
+            }
+        }
+    }
+}
+
+impl Quantizer {
+    pub fn new(metric: DistanceMetric, precision: VectorPrecision) -> Self {
+        Quantizer {
+            metric,
+            precision,
+            scales: None,
+        }
+    }
+
+    /// Int8 needs to learn its scales first, until then vectors are stored as f32.
+    pub fn needs_training(&self) -> bool {
+        self.precision == VectorPrecision::Int8
+            && self.metric != DistanceMetric::Hamming
+            && self.scales.is_none()
+    }
+
+    /// Learns the scale of every dimension from the largest absolute value
+    /// seen in `vectors`. Values outside that range are clamped when encoding.
+    pub fn train(&mut self, vectors: &[Vec<f64>]) {
+        let dimension = vectors.first().map_or(0, |v| v.len());
+        let mut max_abs = vec![0.0f64; dimension];
+        for v in vectors {
+            for (max, value) in max_abs.iter_mut().zip(v) {
+                *max = max.max(value.abs());
+            }
+        }
+        self.scales = Some(
+            max_abs
+                .into_iter()
+                .map(|max| max.max(f64::EPSILON) / i8::MAX as f64)
+                .collect(),
+        );
+    }
+
+    /// Learns the int8 scales from vectors stored in f32 so far and converts
+    /// them to int8 in place.
+    pub fn train_and_encode<'a, I: IntoIterator<Item = &'a mut StoredVector>>(
+        &mut self,
+        vectors: I,
+    ) {
+        let mut stored: Vec<_> = vectors.into_iter().collect();
+        let decoded: Vec<Vec<f64>> = stored.iter().map(|v| self.decode(v)).collect();
+        self.train(&decoded);
+        for (v, decoded) in stored.iter_mut().zip(&decoded) {
+            **v = self.encode(decoded);
+        }
+    }
+
+    pub fn encode(&self, v: &[f64]) -> StoredVector {
+        if self.metric == DistanceMetric::Hamming {
+            return Self::encode_bits(v);
+        }
+        match (self.precision, &self.scales) {
+            (VectorPrecision::F16, _) => {
+                StoredVector::F16(v.iter().map(|&x| f16::from_f64(x)).collect())
+            }
+            (VectorPrecision::Int8, Some(scales)) => StoredVector::Int8(
+                v.iter()
+                    .zip(scales)
+                    .map(|(&x, &scale)| (x / scale).round().clamp(-127.0, 127.0) as i8)
+                    .collect(),
+            ),
+            _ => StoredVector::F32(v.iter().map(|&x| x as f32).collect()),
+        }
+    }
+
+    /// Queries are compared with the stored vectors without quantizing them
+    /// to int8 or f16, which keeps the error of the distance smaller.
+    pub fn encode_query(&self, v: &[f64]) -> StoredVector {
+        if self.metric == DistanceMetric::Hamming {
+            return Self::encode_bits(v);
+        }
+        StoredVector::F32(v.iter().map(|&x| x as f32).collect())
+    }
+
+    fn encode_bits(v: &[f64]) -> StoredVector {
+        let mut words = vec![0u64; v.len().div_ceil(64)];
+        for (bit, _) in v.iter().enumerate().filter(|(_, &x)| x > 0.5) {
+            words[bit / 64] |= 1 << (bit % 64);
+        }
+        StoredVector::Bits(words, v.len())
+    }
+
+    pub fn decode(&self, v: &StoredVector) -> Vec<f64> {
+        self.values(v).collect()
+    }
+
+    fn values<'a>(&'a self, v: &'a StoredVector) -> Values<'a> {
+        match v {
+            StoredVector::F32(values) => Values::F32(values.iter()),
+            StoredVector::F16(values) => Values::F16(values.iter()),
+            StoredVector::Int8(values) => Values::Int8(
+                values.iter(),
+                self.scales.as_deref().unwrap_or_default().iter(),
+            ),
+            StoredVector::Bits(words, len) => Values::Bits(words, 0..*len),
+        }
+    }
+
+    pub fn float_distance(&self, a: &StoredVector, b: &StoredVector) -> f64 {
+        match (a, b) {
+            // the Hamming distance of packed bits is the number of set bits in
+            // their difference
+            (StoredVector::Bits(a, _), StoredVector::Bits(b, _)) => {
+                a.iter()
+                    .zip(b)
+                    .map(|(a, b)| (a ^ b).count_ones())
+                    .sum::<u32>() as f64
+            }
+            _ => self
+                .metric
+                .pairs_distance(self.values(a).zip(self.values(b))),
+        }
+    }
+}
+
+impl Metric<StoredVector> for Quantizer {
+    type Unit = u64;
+    fn distance(&self, a: &StoredVector, b: &StoredVector) -> u64 {
+        to_ordered_bits(self.float_distance(a, b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn max_error(quantizer: &Quantizer, v: &[f64]) -> f64 {
+        quantizer
+            .decode(&quantizer.encode(v))
+            .iter()
+            .zip(v)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn test_precisions() {
+        let v = vec![0.5, -1.25, 3.1, 0.001];
+        let f32_quantizer = Quantizer::new(DistanceMetric::Euclidean, VectorPrecision::F32);
+        assert!(max_error(&f32_quantizer, &v) < 1e-6);
+        let f16_quantizer = Quantizer::new(DistanceMetric::Euclidean, VectorPrecision::F16);
+        assert!(max_error(&f16_quantizer, &v) < 1e-2);
+
+        let mut int8_quantizer = Quantizer::new(DistanceMetric::Euclidean, VectorPrecision::Int8);
+        assert!(int8_quantizer.needs_training());
+        assert!(matches!(int8_quantizer.encode(&v), StoredVector::F32(_)));
+        int8_quantizer.train(&[v.clone(), vec![-2.0, 2.0, 0.0, 0.0]]);
+        assert!(!int8_quantizer.needs_training());
+        assert!(matches!(int8_quantizer.encode(&v), StoredVector::Int8(_)));
+        // error is at most half a step of the largest dimension
+        assert!(max_error(&int8_quantizer, &v) <= 3.1 / 127.0 / 2.0 + 1e-12);
+        // out of range values are clamped
+        let clamped = int8_quantizer.decode(&int8_quantizer.encode(&[10.0, 0.0, 0.0, 0.0]));
+        assert!((clamped[0] - 2.0).abs() < 1e-9);
+
+        let query = int8_quantizer.encode_query(&[1.0, 1.0, 1.0, 1.0]);
+        let exact = DistanceMetric::Euclidean.float_distance(&[1.0, 1.0, 1.0, 1.0], &v);
+        let distance = int8_quantizer.float_distance(&query, &int8_quantizer.encode(&v));
+        assert!((distance - exact).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_bits() {
+        let quantizer = Quantizer::new(DistanceMetric::Hamming, VectorPrecision::Int8);
+        assert!(!quantizer.needs_training());
+        let a: Vec<f64> = (0..70).map(|i| (i % 3 == 0) as u8 as f64).collect();
+        let b: Vec<f64> = (0..70).map(|i| (i % 5 == 0) as u8 as f64).collect();
+        let stored = quantizer.encode(&a);
+        match &stored {
+            StoredVector::Bits(words, len) => assert_eq!((words.len(), *len), (2, 70)),
+            _ => panic!("Hamming vectors are stored as bits"),
+        }
+        assert_eq!(quantizer.decode(&stored), a);
+        let distance = quantizer.float_distance(&quantizer.encode_query(&b), &stored);
+        assert_eq!(distance, DistanceMetric::Hamming.float_distance(&a, &b));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::index::db::{AnnNeighbor, CollectionIndex, DistanceMetric, IndexConfig, SearchIndex};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+fn default_oversample() -> usize {
+    4
+}
+
+/// Re-ranks approximate results: `oversample` times as many candidates as
+/// results are fetched from the index, then their distances to the query are
+/// recomputed from the full vectors, which the index only keeps when its
+/// config sets `full_vectors`. A larger pool finds neighbors the graph search
+/// missed, the exact distances fix the order of candidates whose distances
+/// were rounded.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Rerank {
+    #[serde(default = "default_oversample")]
+    pub oversample: usize,
+}
+
+impl Rerank {
+    pub fn n_candidates(&self, n_results: usize) -> usize {
+        n_results.saturating_mul(self.oversample.max(1))
+    }
+}
+
+/// The vectors as they were inserted, in double precision whatever the
+/// precision of the index. Empty unless the config sets `full_vectors`. The
+/// lock is always taken last, after the locks of the index.
+#[derive(Clone, Default)]
+pub struct FullVectors(Option<Arc<RwLock<VectorsById>>>);
+
+type VectorsById = HashMap<String, Vec<f64>>;
+
+impl FullVectors {
+    pub fn new(config: &IndexConfig) -> Self {
+        FullVectors(
+            config
+                .full_vectors
+                .then(|| Arc::new(RwLock::new(HashMap::new()))),
+        )
+    }
+
+    pub fn insert(&self, id: &str, v: &[f64]) {
+        if let Some(vectors) = &self.0 {
+            vectors.write().unwrap().insert(id.to_string(), v.to_vec());
+        }
+    }
+
+    pub fn remove(&self, id: &str) {
+        if let Some(vectors) = &self.0 {
+            vectors.write().unwrap().remove(id);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Vec<f64>> {
+        self.0.as_ref()?.read().unwrap().get(id).cloned()
+    }
+}
+
+impl Serialize for FullVectors {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.0 {
+            Some(vectors) => Some(&*vectors.read().unwrap()).serialize(serializer),
+            None => None::<&VectorsById>.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FullVectors {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let vectors = Option::<VectorsById>::deserialize(deserializer)?;
+        Ok(FullVectors(
+            vectors.map(|vectors| Arc::new(RwLock::new(vectors))),
+        ))
+    }
+}
+
+/// Sorts `neighbors` by their exact distance to `v` and keeps the `k`
+/// nearest. Neighbors removed since the search, or without a full vector,
+/// are dropped.
+pub fn rerank(
+    index: &CollectionIndex,
+    metric: DistanceMetric,
+    v: &[f64],
+    neighbors: Vec<AnnNeighbor>,
+    k: usize,
+) -> Vec<AnnNeighbor> {
+    let mut neighbors: Vec<AnnNeighbor> = neighbors
+        .into_iter()
+        .filter_map(|mut neighbor| {
+            let full = index.full_vector(&neighbor.id)?;
+            neighbor.distance = metric.float_distance(v, &full);
+            neighbor.similarity = metric.similarity(neighbor.distance);
+            Some(neighbor)
+        })
+        .collect();
+    neighbors.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    neighbors.truncate(k);
+    neighbors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::db::{IndexBackend, IndexConfig};
+    use crate::index::quantization::VectorPrecision;
+    use crate::index::test_utils::random_vectors;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_rerank() {
+        let metric = DistanceMetric::Euclidean;
+        let index = CollectionIndex::new(&IndexConfig {
+            precision: VectorPrecision::F16,
+            full_vectors: true,
+            ..Default::default()
+        });
+        let flat = CollectionIndex::new(&IndexConfig {
+            backend: IndexBackend::Flat,
+            ..Default::default()
+        });
+
+        let mut vectors = random_vectors(520, 8, 0x2545_f491_4f6c_dd1d);
+        let queries = vectors.split_off(500);
+        for (i, v) in vectors.iter().enumerate() {
+            index.insert(v.clone(), i.to_string());
+            flat.insert(v.clone(), i.to_string());
+        }
+        assert!(flat.full_vector("0").is_none());
+
+        let options = Rerank { oversample: 4 };
+        assert_eq!(options.n_candidates(10), 40);
+        let (mut found, mut found_reranked) = (0, 0);
+        for query in queries {
+            let expected: HashSet<String> = flat
+                .search(&query, 10, 10)
+                .into_iter()
+                .map(|n| n.id)
+                .collect();
+            found += index
+                .search(&query, 10, 10)
+                .into_iter()
+                .filter(|n| expected.contains(&n.id))
+                .count();
+
+            let candidates = index.search(&query, options.n_candidates(10), 10);
+            let reranked = rerank(&index, metric, &query, candidates, 10);
+            assert_eq!(reranked.len(), 10);
+            for pair in reranked.windows(2) {
+                assert!(pair[0].distance <= pair[1].distance);
+            }
+            // distances are exact, not the rounded ones of the stored vectors
+            for neighbor in &reranked {
+                let inserted = &vectors[neighbor.id.parse::<usize>().unwrap()];
+                assert_eq!(neighbor.distance, metric.float_distance(&query, inserted));
+            }
+            found_reranked += reranked.iter().filter(|n| expected.contains(&n.id)).count();
+        }
+        assert!(found_reranked >= found);
+        assert!(found_reranked as f64 / 200.0 > 0.95);
+
+        // removed images are dropped
+        let candidates = index.search(&[0.5; 8], 5, 10);
+        let removed_id = candidates[0].id.clone();
+        index.remove(removed_id.clone());
+        assert_eq!(rerank(&index, metric, &[0.5; 8], candidates, 5).len(), 4);
+        assert!(index.full_vector(&removed_id).is_none());
+
+        // the full vectors are part of the snapshot
+        let config = IndexConfig {
+            precision: VectorPrecision::F16,
+            full_vectors: true,
+            ..Default::default()
+        };
+        let mut buffer = Vec::new();
+        index.save(&mut buffer).unwrap();
+        let loaded = CollectionIndex::load(&config, buffer.as_slice()).unwrap();
+        assert_eq!(loaded.full_vector("7").unwrap(), vectors[7]);
+        assert!(loaded.full_vector(&removed_id).is_none());
+    }
+}
@@ -0,0 +1,17 @@
+/// `n` vectors of `dimension` values in [0, 1), the same ones for the same
+/// seed. Xorshift, good enough for test data.
+pub fn random_vectors(n: usize, dimension: usize, seed: u64) -> Vec<Vec<f64>> {
+    let mut state = seed;
+    (0..n)
+        .map(|_| {
+            (0..dimension)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    (state % 1000) as f64 / 1000.0
+                })
+                .collect()
+        })
+        .collect()
+}
@@ -0,0 +1,327 @@
+use crate::index::events::{AddImage, Event, ImageBytes, ImageSource, UploadQueued};
+use crate::state::app::CollectionName;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::error::Error;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+pub const WAL_FILE: &str = "events.log";
+// the log a snapshot is being written for, replayed before `WAL_FILE`
+pub const PREVIOUS_WAL_FILE: &str = "events.previous.log";
+// the bytes of uploaded images which are not indexed yet, one file per upload
+pub const UPLOADS_DIR: &str = "uploads";
+
+// every record is: payload length (u32 LE), crc32 of the payload (u32 LE), bincode payload
+const HEADER_LEN: usize = 8;
+
+/// Append-only log of mutating events. Every event is fsync'd before `append`
+/// returns so an acknowledged request survives a crash.
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+    // uploads the current log refers to, by collection and id of the image
+    uploads: HashMap<(CollectionName, String), Vec<PathBuf>>,
+    next_upload: u64,
+}
+
+impl WriteAheadLog {
+    pub fn open(data_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(WAL_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let uploads_dir = data_dir.join(UPLOADS_DIR);
+        fs::create_dir_all(&uploads_dir)?;
+        let mut next_upload = 0;
+        for entry in fs::read_dir(&uploads_dir)? {
+            if let Some(n) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<u64>().ok())
+            {
+                next_upload = next_upload.max(n + 1);
+            }
+        }
+        Ok(WriteAheadLog {
+            path,
+            file,
+            uploads: HashMap::new(),
+            next_upload,
+        })
+    }
+
+    /// Once an image is indexed or removed the uploads of its earlier
+    /// `AddImage` are not replayed anymore and are deleted.
+    pub fn append(&mut self, event: &Event) -> Result<(), Box<dyn Error>> {
+        let record = self.encode(event)?;
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+        let key = match event {
+            Event::ImageIndexed(image_indexed) => {
+                (&image_indexed.collection_name, &image_indexed.id)
+            }
+            Event::RemoveImage(remove_image) => (&remove_image.collection_name, &remove_image.id),
+            _ => return Ok(()),
+        };
+        if let Some(paths) = self.uploads.remove(&(key.0.clone(), key.1.clone())) {
+            for path in paths {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The bytes of an uploaded image are saved to a file of their own instead
+    /// of the record, the log does not grow by the size of every upload.
+    fn encode(&mut self, event: &Event) -> Result<Vec<u8>, Box<dyn Error>> {
+        let (add_image, image) = match event {
+            Event::AddImage(
+                add_image @ AddImage {
+                    source: ImageSource::ImageBytes(image),
+                    ..
+                },
+            ) => (add_image, image),
+            _ => return encode_record(event),
+        };
+
+        let upload = self.next_upload.to_string();
+        self.next_upload += 1;
+        let path = self.path.with_file_name(UPLOADS_DIR).join(&upload);
+        let mut file = File::create(&path)?;
+        file.write_all(&image.bytes)?;
+        file.sync_all()?;
+        self.uploads
+            .entry((add_image.collection_name.clone(), add_image.id.clone()))
+            .or_default()
+            .push(path);
+
+        encode_record(&Event::UploadQueued(UploadQueued {
+            add_image: AddImage {
+                source: ImageSource::ImageBytes(ImageBytes { bytes: vec![] }),
+                collection_name: add_image.collection_name.clone(),
+                id: add_image.id.clone(),
+                metadata: add_image.metadata.clone(),
+            },
+            upload,
+        }))
+    }
+
+    /// Replaces the content of the log with `events`. Called after a snapshot
+    /// with the events that the snapshot does not cover yet.
+    pub fn reset(&mut self, events: &[Event]) -> Result<(), Box<dyn Error>> {
+        let tmp_path = self.path.with_extension("log.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        // the uploads of the replaced log are deleted by `drop_previous`
+        self.uploads.clear();
+        for event in events {
+            tmp.write_all(&self.encode(event)?)?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    /// Starts a new log with `events` and keeps the current one as the
+    /// previous log until `drop_previous`, which is called once a snapshot
+    /// covering it is written. If the last snapshot failed the previous log
+    /// is still there, the current one is appended to it.
+    pub fn rotate(&mut self, events: &[Event]) -> Result<(), Box<dyn Error>> {
+        let previous = self.path.with_file_name(PREVIOUS_WAL_FILE);
+        if previous.exists() {
+            let mut file = OpenOptions::new().append(true).open(&previous)?;
+            io::copy(&mut File::open(&self.path)?, &mut file)?;
+            file.sync_all()?;
+        } else {
+            fs::rename(&self.path, &previous)?;
+        }
+        self.reset(events)
+    }
+
+    /// Also deletes the uploads which the current log does not refer to,
+    /// the ones of the previous log and of records lost in a crash.
+    pub fn drop_previous(&mut self) -> Result<(), Box<dyn Error>> {
+        let previous = self.path.with_file_name(PREVIOUS_WAL_FILE);
+        if previous.exists() {
+            fs::remove_file(previous)?;
+        }
+        let referenced: HashSet<&PathBuf> = self.uploads.values().flatten().collect();
+        for entry in fs::read_dir(self.path.with_file_name(UPLOADS_DIR))? {
+            let path = entry?.path();
+            if !referenced.contains(&path) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn encode_record(event: &Event) -> Result<Vec<u8>, Box<dyn Error>> {
+    let payload = bincode::serialize(event)?;
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// The `AddImage` of an upload, with the bytes read back from its file.
+pub fn read_upload(
+    data_dir: &Path,
+    upload_queued: UploadQueued,
+) -> Result<AddImage, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    File::open(data_dir.join(UPLOADS_DIR).join(&upload_queued.upload))?.read_to_end(&mut bytes)?;
+    Ok(AddImage {
+        source: ImageSource::ImageBytes(ImageBytes { bytes }),
+        ..upload_queued.add_image
+    })
+}
+
+/// Reads all events from the logs in `data_dir`, the previous log first.
+pub fn read_events(data_dir: &Path) -> Result<Vec<Event>, Box<dyn Error>> {
+    let mut events = read_log(&data_dir.join(PREVIOUS_WAL_FILE))?;
+    events.extend(read_log(&data_dir.join(WAL_FILE))?);
+    Ok(events)
+}
+
+/// A crash can leave a partially written record at the end of the log. Reading
+/// stops at the first incomplete or corrupted record and the log is truncated
+/// to the last valid one.
+fn read_log(path: &Path) -> Result<Vec<Event>, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while offset + HEADER_LEN <= bytes.len() {
+        let len = u32::from_le_bytes(bytes[offset..offset + 4].try_into()?) as usize;
+        let crc = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into()?);
+        let start = offset + HEADER_LEN;
+        if start + len > bytes.len() {
+            break;
+        }
+        let payload = &bytes[start..start + len];
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        match bincode::deserialize(payload) {
+            Ok(event) => events.push(event),
+            Err(_) => break,
+        }
+        offset = start + len;
+    }
+
+    if offset < bytes.len() {
+        println!(
+            "Dropping {} bytes of corrupted events at the end of {:?}",
+            bytes.len() - offset,
+            path
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(offset as u64)?;
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::events::{RemoveCollection, RemoveImage};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("visual-search-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn remove_image(id: &str) -> Event {
+        Event::RemoveImage(RemoveImage {
+            collection_name: "images".into(),
+            id: id.into(),
+        })
+    }
+
+    fn removed_id(event: &Event) -> String {
+        match event {
+            Event::RemoveImage(remove_image) => remove_image.id.clone(),
+            _ => panic!("Unexpected event"),
+        }
+    }
+
+    #[test]
+    fn test_append_reset_read() {
+        let dir = test_dir("wal-append");
+        let mut wal = WriteAheadLog::open(&dir).unwrap();
+        wal.append(&remove_image("a")).unwrap();
+        wal.append(&remove_image("b")).unwrap();
+
+        let events = read_events(&dir).unwrap();
+        assert_eq!(
+            events.iter().map(removed_id).collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+
+        wal.reset(&[remove_image("c")]).unwrap();
+        wal.append(&remove_image("d")).unwrap();
+        let events = read_events(&dir).unwrap();
+        assert_eq!(
+            events.iter().map(removed_id).collect::<Vec<_>>(),
+            ["c", "d"]
+        );
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = test_dir("wal-rotate");
+        let mut wal = WriteAheadLog::open(&dir).unwrap();
+        wal.append(&remove_image("a")).unwrap();
+        wal.rotate(&[remove_image("b")]).unwrap();
+        wal.append(&remove_image("c")).unwrap();
+        let ids = || -> Vec<String> { read_events(&dir).unwrap().iter().map(removed_id).collect() };
+        assert_eq!(ids(), ["a", "b", "c"]);
+
+        // without a snapshot in between the previous log keeps growing
+        wal.rotate(&[]).unwrap();
+        wal.append(&remove_image("d")).unwrap();
+        assert_eq!(ids(), ["a", "b", "c", "d"]);
+
+        wal.drop_previous().unwrap();
+        assert_eq!(ids(), ["d"]);
+    }
+
+    #[test]
+    fn test_corrupted_tail() {
+        let dir = test_dir("wal-corrupted");
+        let mut wal = WriteAheadLog::open(&dir).unwrap();
+        wal.append(&remove_image("a")).unwrap();
+        wal.append(&Event::RemoveCollection(RemoveCollection {
+            name: "images".into(),
+        }))
+        .unwrap();
+        let valid_len = fs::metadata(dir.join(WAL_FILE)).unwrap().len();
+        drop(wal);
+
+        // simulate a crash in the middle of writing a record
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(WAL_FILE))
+            .unwrap();
+        file.write_all(&encode_record(&remove_image("b")).unwrap()[..10])
+            .unwrap();
+
+        let events = read_events(&dir).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(fs::metadata(dir.join(WAL_FILE)).unwrap().len(), valid_len);
+    }
+}
@@ -1,18 +1,37 @@
+use crate::image_transform::color::ColorHistogram;
+use crate::image_transform::hashing::PerceptualHash;
 use crate::image_transform::models::{LoadedModel, ModelArchitecture, ModelConfig};
 use crate::image_transform::utils::{image_from_bytes, read_bytes_url};
-use crate::index::db::VectorIndex;
+use crate::index::clusters::{cluster_index, Cluster, ClusterMember};
+use crate::index::db::{
+    AnnNeighbor, CollectionIndex, DistanceMetric, IndexConfig, SearchIndex, DEFAULT_EF,
+    MAX_RANGE_RESULTS,
+};
+use crate::index::duplicates::{find_duplicates as find_duplicates_in_index, DuplicateGroup};
 use crate::index::events::{
-    AddImage, ImageBytes, ImageSource, RemoveCollection, RemoveImage, SearchImage, UpsertCollection,
+    AddImage, BatchSearch, ClusterCollection, Event, FederatedSearch, FindDuplicates, FitPca,
+    GetClusterMembers, GetClusters, GetDuplicates, ImageBytes, ImageIndexed, ImageSource,
+    QuerySource, RangeSearch, RemoveCollection, RemoveImage, SearchImage, SearchSimilar,
+    UploadQueued, UpsertCollection, WeightedQuery,
 };
+use crate::index::metadata::{Filter, Metadata};
+use crate::index::mmr::Mmr;
+use crate::index::pca::{Pca, PcaConfig};
+use crate::index::rerank::{rerank, Rerank};
+use crate::index::wal::{read_events, read_upload, WriteAheadLog};
+use crate::state::parallel::parallel_map;
+use crate::state::persistence::{read_snapshot, write_snapshot};
 use crate::state::work_queue::WorkQueue;
-use image::{ImageBuffer, Rgb};
+use image::RgbImage;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
-use std::sync::{Arc, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 pub type CollectionName = String;
 pub type ImageId = String;
@@ -21,12 +40,94 @@ pub type ModelId = String;
 #[derive(Clone, PartialEq)]
 pub enum Job {
     AddImage(AddImage),
+    FindDuplicates(FindDuplicates),
+    ClusterCollection(ClusterCollection),
+    FitPca(FitPca),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed(String),
+}
+
+/// The last background job of type `R` over a collection and what it found.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct JobReport<R, T> {
+    pub request: R,
+    pub status: JobStatus,
+    pub results: Vec<T>,
+}
+
+impl<R, T: Serialize> JobReport<R, T> {
+    pub fn queued(request: R) -> Self {
+        Self {
+            request,
+            status: JobStatus::Queued,
+            results: vec![],
+        }
+    }
+
+    /// One result per line.
+    pub fn to_jsonl(&self) -> Result<String, serde_json::Error> {
+        let mut jsonl = String::new();
+        for result in &self.results {
+            jsonl.push_str(&serde_json::to_string(result)?);
+            jsonl.push('\n');
+        }
+        Ok(jsonl)
+    }
+}
+
+/// Near-duplicate groups of a collection found by a `FindDuplicates` job.
+pub type DuplicateReport = JobReport<FindDuplicates, DuplicateGroup>;
+
+/// Clusters of a collection found by a `ClusterCollection` job.
+pub type ClusterReport = JobReport<ClusterCollection, Cluster>;
+
+type JobReports<R, T> = Arc<RwLock<HashMap<CollectionName, JobReport<R, T>>>>;
+
+/// Sets the status and results of the report of `request`, unless it was
+/// replaced by a newer job or the collection removed.
+fn update_report<R: PartialEq, T>(
+    reports: &JobReports<R, T>,
+    collection_name: &str,
+    request: &R,
+    status: JobStatus,
+    results: Vec<T>,
+) -> Result<(), Box<dyn Error>> {
+    let mut reports = reports.write().map_err(|_| "RwLock Error")?;
+    if let Some(report) = reports.get_mut(collection_name) {
+        if report.request == *request {
+            report.status = status;
+            report.results = results;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ClusterOverview {
+    pub centroid: Vec<f64>,
+    pub size: usize,
+    // the images closest to the centroid
+    pub central: Vec<ClusterMember>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ClustersResult {
+    pub collection_name: String,
+    pub status: JobStatus,
+    pub clusters: Vec<ClusterOverview>,
 }
 
 #[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct SingleImageResult {
     pub id: String,
-    pub similarity: u32,
+    pub distance: f64,
+    pub similarity: f64,
 }
 
 #[derive(Clone, Serialize, Deserialize, JsonSchema)]
@@ -35,45 +136,493 @@ pub struct ImageResult {
     pub results: Vec<SingleImageResult>,
 }
 
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct FederatedImageResult {
+    pub collection_name: String,
+    pub id: String,
+    pub distance: f64,
+    pub similarity: f64,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct FederatedResult {
+    pub results: Vec<FederatedImageResult>,
+}
+
+/// Results of one query of a batch search, or why it failed.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BatchItemResult {
+    pub results: Vec<SingleImageResult>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BatchSearchResult {
+    pub collection_name: String,
+    pub results: Vec<BatchItemResult>,
+}
+
 #[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub enum GenericModelConfig {
     ModelConfig(ModelConfig),
     ModelArchitecture(ModelArchitecture),
+    ExternalEmbedding(ExternalEmbedding),
+    // no neural network, indexed with hamming distance
+    PerceptualHash(PerceptualHash),
+    // no neural network, colour distribution of the image
+    ColorHistogram(ColorHistogram),
+}
+
+/// A collection without a model which stores vectors computed elsewhere.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ExternalEmbedding {
+    pub dimension: usize,
+}
+
+// a query after downloading and decoding its image, so that no lock is held
+// while waiting for the network
+#[derive(Clone)]
+enum Input {
+    Image(RgbImage),
+    Vector(Vec<f64>),
+    // an image which is already in the collection
+    Id(ImageId),
+}
+
+// distances between vectors of collections with the same embedding space
+// and metric can be compared
+#[derive(Debug, PartialEq)]
+enum EmbeddingSpace {
+    Model {
+        model_name: String,
+        layer_name: Option<String>,
+    },
+    External {
+        dimension: usize,
+    },
+    PerceptualHash(PerceptualHash),
+    ColorHistogram(ColorHistogram),
 }
 
 #[derive(Clone)]
 pub struct Collection {
     pub name: String,
     pub model_config: GenericModelConfig,
-    pub model: LoadedModel,
-    pub index: VectorIndex,
+    pub index_config: IndexConfig,
+    // `None` for external embeddings and handcrafted features
+    pub model: Option<LoadedModel>,
+    pub index: CollectionIndex,
+    // fitted projection of `index_config.pca`, `None` until it is fitted
+    pub pca: Option<Pca>,
+    // only images with metadata have an entry
+    pub metadata: Arc<RwLock<HashMap<ImageId, Metadata>>>,
+    // changes made while the PCA is fitted, None if it is not being fitted
+    pca_changes: Arc<Mutex<Option<Vec<PcaChange>>>>,
+}
+
+#[derive(Clone)]
+enum PcaChange {
+    // features before the projection
+    Insert(ImageId, Vec<f64>),
+    Remove(ImageId),
+}
+
+/// The vectors of a collection, copied to fit its PCA without locking it.
+struct PcaFit {
+    collection_name: CollectionName,
+    config: PcaConfig,
+    index_config: IndexConfig,
+    items: Vec<(ImageId, Vec<f64>)>,
+    changes: Arc<Mutex<Option<Vec<PcaChange>>>>,
+}
+
+struct FittedPca {
+    pca: Pca,
+    index: CollectionIndex,
+    changes: Arc<Mutex<Option<Vec<PcaChange>>>>,
+}
+
+impl PcaFit {
+    /// Fits the PCA on `sample`, or on the copied vectors if there is none,
+    /// and indexes the copied vectors projected.
+    fn fit(self, sample: Option<Vec<Vec<f64>>>) -> FittedPca {
+        let sample = sample.unwrap_or_else(|| self.items.iter().map(|(_, v)| v.clone()).collect());
+        println!(
+            "Fitting PCA of {} on {} vectors",
+            self.collection_name,
+            sample.len()
+        );
+        let pca = Pca::fit(&sample, &self.config);
+        let index = CollectionIndex::new(&self.index_config);
+        for (id, v) in self.items {
+            index.insert(pca.project(&v), id);
+        }
+        FittedPca {
+            pca,
+            index,
+            changes: self.changes,
+        }
+    }
 }
 
 impl Collection {
-    pub fn new(name: &str, model_config: &GenericModelConfig) -> Self {
+    pub fn new(name: &str, model_config: &GenericModelConfig, index_config: &IndexConfig) -> Self {
+        let mut index_config = index_config.clone();
+        if let GenericModelConfig::PerceptualHash(_) = model_config {
+            // hashes are compared bit by bit unless the config says otherwise
+            index_config.metric.get_or_insert(DistanceMetric::Hamming);
+        }
+        let index = CollectionIndex::new(&index_config);
+        Collection::with_index(name, model_config, &index_config, index)
+    }
+
+    pub fn with_index(
+        name: &str,
+        model_config: &GenericModelConfig,
+        index_config: &IndexConfig,
+        index: CollectionIndex,
+    ) -> Self {
         let model = match model_config {
             GenericModelConfig::ModelConfig(config) => {
-                LoadedModel::new_from_config((*config).clone())
+                Some(LoadedModel::new_from_config((*config).clone()))
             }
             GenericModelConfig::ModelArchitecture(architecture) => {
-                LoadedModel::new_from_architecture((*architecture).clone())
+                Some(LoadedModel::new_from_architecture((*architecture).clone()))
             }
+            GenericModelConfig::ExternalEmbedding(_)
+            | GenericModelConfig::PerceptualHash(_)
+            | GenericModelConfig::ColorHistogram(_) => None,
         };
 
         Collection {
             name: name.to_string(),
             model_config: model_config.clone(),
-            model: model,
-            index: VectorIndex::new(),
+            index_config: index_config.clone(),
+            model,
+            index,
+            pca: None,
+            metadata: Arc::new(Default::default()),
+            pca_changes: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Checks that the collection can index `source`: images need a model or
+    /// handcrafted features and vectors must have the dimension of the
+    /// collection.
+    pub fn check_source(&self, source: &ImageSource) -> Result<(), Box<dyn Error>> {
+        match source {
+            ImageSource::Vector(vector) => self.check_dimension(vector.len()),
+            _ if matches!(self.model_config, GenericModelConfig::ExternalEmbedding(_)) => {
+                Err(format!("Collection {} has no model, add vectors instead", self.name).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_dimension(&self, dimension: usize) -> Result<(), Box<dyn Error>> {
+        match self.dimension() {
+            Some(expected) if expected != dimension => Err(format!(
+                "Collection {} expects vectors of dimension {}, got {}",
+                self.name, expected, dimension
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects index configs which do not fit the vectors of the collection.
+    fn check_config(&self) -> Result<(), Box<dyn Error>> {
+        match &self.model_config {
+            GenericModelConfig::ColorHistogram(histogram) => histogram.check()?,
+            GenericModelConfig::PerceptualHash(hash) => {
+                hash.check()?;
+                // hashes are only compared bit by bit
+                if self.index_config.metric() != DistanceMetric::Hamming
+                    || self.index_config.pca.is_some()
+                {
+                    return Err(format!(
+                        "Collection {} of perceptual hashes needs the Hamming metric and no PCA",
+                        self.name
+                    )
+                    .into());
+                }
+            }
+            _ => {}
+        }
+        if let Some(pca) = &self.index_config.pca {
+            if pca.dimension == 0 {
+                return Err(
+                    format!("The PCA of collection {} needs a dimension", self.name).into(),
+                );
+            }
+            match self.dimension() {
+                Some(dimension) if pca.dimension > dimension => {
+                    return Err(format!(
+                        "Collection {} has vectors of dimension {}, a PCA cannot project them \
+                         to {} dimensions",
+                        self.name, dimension, pca.dimension
+                    )
+                    .into());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Length of the vectors of the collection before any projection, `None`
+    /// if the model does not tell.
+    fn dimension(&self) -> Option<usize> {
+        match (&self.model, &self.model_config) {
+            (Some(model), _) => model.dimension(),
+            (None, GenericModelConfig::ExternalEmbedding(embedding)) => Some(embedding.dimension),
+            (None, GenericModelConfig::PerceptualHash(hash)) => Some(hash.dimension()),
+            (None, GenericModelConfig::ColorHistogram(histogram)) => Some(histogram.dimension()),
+            (None, _) => {
+                unreachable!("only external embeddings and handcrafted features have no model")
+            }
+        }
+    }
+
+    /// Picks `n_results` of the results with maximal marginal relevance,
+    /// comparing the stored vectors.
+    fn diversify(
+        &self,
+        results: Vec<SingleImageResult>,
+        mmr: &Mmr,
+        n_results: usize,
+    ) -> Vec<SingleImageResult> {
+        // images removed since the search are dropped
+        let (results, vectors): (Vec<_>, Vec<_>) = results
+            .into_iter()
+            .filter_map(|result| {
+                let vector = self.index.vector(&result.id)?;
+                Some((result, vector))
+            })
+            .unzip();
+        let relevance: Vec<f64> = results.iter().map(|result| result.similarity).collect();
+        mmr.select(self.index_config.metric(), &relevance, &vectors, n_results)
+            .into_iter()
+            .map(|i| results[i].clone())
+            .collect()
+    }
+
+    fn embedding_space(&self) -> EmbeddingSpace {
+        match (&self.model, &self.model_config) {
+            (Some(model), _) => EmbeddingSpace::Model {
+                model_name: model.config.model_name.clone(),
+                layer_name: model.config.layer_name.clone(),
+            },
+            (None, GenericModelConfig::ExternalEmbedding(embedding)) => EmbeddingSpace::External {
+                dimension: embedding.dimension,
+            },
+            (None, GenericModelConfig::PerceptualHash(hash)) => {
+                EmbeddingSpace::PerceptualHash(hash.clone())
+            }
+            (None, GenericModelConfig::ColorHistogram(histogram)) => {
+                EmbeddingSpace::ColorHistogram(histogram.clone())
+            }
+            (None, _) => {
+                unreachable!("only external embeddings and handcrafted features have no model")
+            }
         }
     }
+
+    /// Features of `input` in the space of the index, projected with the
+    /// PCA once it is fitted.
+    fn extract_features(&self, input: Input) -> Result<Vec<f64>, Box<dyn Error>> {
+        match input {
+            // stored vectors are already projected
+            Input::Id(id) => Ok(self
+                .index
+                .vector(&id)
+                .ok_or_else(|| format!("Unknown image id {}", id))?),
+            input => Ok(self.project(self.embed(input)?)),
+        }
+    }
+
+    /// Features of `input` in the space of the model, before any projection.
+    fn embed(&self, input: Input) -> Result<Vec<f64>, Box<dyn Error>> {
+        match (input, &self.model) {
+            (Input::Vector(vector), _) => {
+                self.check_dimension(vector.len())?;
+                Ok(vector)
+            }
+            (Input::Id(id), _) => Err(format!("Cannot embed image {} by id", id).into()),
+            (Input::Image(image), Some(model)) => {
+                println!("Extracting features");
+                Ok(model.extract_features(image)?)
+            }
+            (Input::Image(image), None) => match &self.model_config {
+                GenericModelConfig::PerceptualHash(hash) => Ok(hash.hash(&image)),
+                GenericModelConfig::ColorHistogram(histogram) => {
+                    Ok(histogram.extract_features(&image))
+                }
+                _ => Err(
+                    format!("Collection {} has no model, add vectors instead", self.name).into(),
+                ),
+            },
+        }
+    }
+
+    fn project(&self, features: Vec<f64>) -> Vec<f64> {
+        match &self.pca {
+            Some(pca) => pca.project(&features),
+            None => features,
+        }
+    }
+
+    /// Indexes features given in the space of the model. Returns true once
+    /// the collection has enough images to fit its PCA.
+    fn add_features(&self, features: Vec<f64>, id: String) -> Result<bool, Box<dyn Error>> {
+        let mut changes = self.pca_changes.lock().map_err(|_| "Mutex Error")?;
+        self.index
+            .insert(self.project(features.clone()), id.clone());
+        if let Some(changes) = changes.as_mut() {
+            changes.push(PcaChange::Insert(id, features));
+            return Ok(false);
+        }
+        Ok(self.pca.is_none()
+            && self
+                .index_config
+                .pca
+                .is_some_and(|config| self.index.len() >= config.n_training()))
+    }
+
+    fn remove_image(&self, id: &str) -> Result<bool, Box<dyn Error>> {
+        let mut changes = self.pca_changes.lock().map_err(|_| "Mutex Error")?;
+        self.metadata
+            .write()
+            .map_err(|_| "RwLock Error")?
+            .remove(id);
+        let removed = self.index.remove(id.to_string());
+        if let (true, Some(changes)) = (removed, changes.as_mut()) {
+            changes.push(PcaChange::Remove(id.to_string()));
+        }
+        Ok(removed)
+    }
+
+    /// Copies the stored vectors and starts recording the changes to the
+    /// index, which go on while the PCA is fitted without holding any lock.
+    /// Returns `None` if the PCA is already fitted or being fitted.
+    fn begin_fit_pca(&self) -> Result<Option<PcaFit>, Box<dyn Error>> {
+        let config = self
+            .index_config
+            .pca
+            .ok_or_else(|| format!("Collection {} has no PCA", self.name))?;
+        let mut changes = self.pca_changes.lock().map_err(|_| "Mutex Error")?;
+        if self.pca.is_some() || changes.is_some() {
+            return Ok(None);
+        }
+        *changes = Some(Vec::new());
+        let mut ids = self.index.ids();
+        ids.sort_unstable();
+        let items = ids
+            .into_iter()
+            .filter_map(|id| {
+                // the full vectors give a more accurate fit when they are kept
+                let v = self
+                    .index
+                    .full_vector(&id)
+                    .or_else(|| self.index.vector(&id))?;
+                Some((id, v))
+            })
+            .collect();
+        Ok(Some(PcaFit {
+            collection_name: self.name.clone(),
+            config,
+            index_config: self.index_config.clone(),
+            items,
+            changes: self.pca_changes.clone(),
+        }))
+    }
+
+    /// Replays the changes recorded since `begin_fit_pca` on the projected
+    /// index and swaps it in. A fitted PCA cannot be fitted again as the
+    /// original vectors are gone.
+    fn finish_fit_pca(&mut self, fitted: FittedPca) -> Result<(), Box<dyn Error>> {
+        let FittedPca {
+            pca,
+            index,
+            changes,
+        } = fitted;
+        // the collection was removed and created again in the meantime
+        if !Arc::ptr_eq(&changes, &self.pca_changes) {
+            return Ok(());
+        }
+        let mut changes = changes.lock().map_err(|_| "Mutex Error")?;
+        for change in changes.take().unwrap_or_default() {
+            match change {
+                PcaChange::Insert(id, v) => index.insert(pca.project(&v), id),
+                PcaChange::Remove(id) => {
+                    index.remove(id);
+                }
+            }
+        }
+        self.index = index;
+        self.pca = Some(pca);
+        Ok(())
+    }
+
+    /// Like `extract_features` for many inputs, the images are run through
+    /// the model in one call. Failed inputs stay failed.
+    fn extract_features_batch(
+        &self,
+        inputs: Vec<Result<Input, String>>,
+    ) -> Vec<Result<Vec<f64>, String>> {
+        let model = match &self.model {
+            Some(model) => model,
+            None => {
+                return parallel_map(inputs, |input| {
+                    input.and_then(|input| self.extract_features(input).map_err(|e| e.to_string()))
+                })
+            }
+        };
+        let mut images = vec![];
+        let mut positions = vec![];
+        let mut features: Vec<Result<Vec<f64>, String>> = inputs
+            .into_iter()
+            .enumerate()
+            .map(|(i, input)| match input {
+                Ok(Input::Image(image)) => {
+                    images.push(image);
+                    positions.push(i);
+                    // filled in once the batch is run
+                    Ok(vec![])
+                }
+                input => {
+                    input.and_then(|input| self.extract_features(input).map_err(|e| e.to_string()))
+                }
+            })
+            .collect();
+        for (i, result) in positions
+            .into_iter()
+            .zip(model.extract_features_batch(images))
+        {
+            features[i] = result.map(|vector| self.project(vector));
+        }
+        features
+    }
 }
 
+#[derive(Clone)]
 pub struct EmbeddingApp {
     pub n_workers: usize,
     pub job_queue: WorkQueue<Job>,
     pub images: Arc<RwLock<HashMap<ImageId, ImageBytes>>>,
     pub collections: Arc<RwLock<HashMap<CollectionName, Collection>>>,
+    pub wal: Option<Arc<Mutex<WriteAheadLog>>>,
+    // latest near-duplicate report of every collection, not persisted
+    pub duplicates: JobReports<FindDuplicates, DuplicateGroup>,
+    // latest clustering of every collection, not persisted
+    pub clusters: JobReports<ClusterCollection, Cluster>,
+    // jobs taken from the queue by a worker but not finished yet
+    in_progress: Arc<Mutex<Vec<Job>>>,
+    // jobs adding an image which was removed while they were running, they
+    // drop the image when they finish
+    cancelled: Arc<Mutex<Vec<Job>>>,
+    // held while a snapshot is written so there is only one at a time
+    snapshot_lock: Arc<Mutex<()>>,
 }
 
 impl EmbeddingApp {
@@ -83,54 +632,365 @@ impl EmbeddingApp {
             job_queue: WorkQueue::new(),
             images: Arc::new(Default::default()),
             collections: Arc::new(Default::default()),
+            wal: None,
+            duplicates: Arc::new(Default::default()),
+            clusters: Arc::new(Default::default()),
+            in_progress: Arc::new(Default::default()),
+            cancelled: Arc::new(Default::default()),
+            snapshot_lock: Arc::new(Mutex::new(())),
         }
     }
 
-    pub fn upsert_collection(&self, upsert_collection: &UpsertCollection) {
-        let mut collections = self.collections.write().unwrap();
-        if collections.contains_key(&upsert_collection.name) {
-            // create a task to rebuild a collection
-            // I think for now we can disable this
-        } else {
-            let collection = Collection::new(&upsert_collection.name, &upsert_collection.config);
-            collections.insert(upsert_collection.name.clone(), collection);
-        }
+    /// Fails without logging anything if the config is invalid, see
+    /// `Collection::check_config`.
+    pub fn upsert_collection(
+        &self,
+        upsert_collection: &UpsertCollection,
+    ) -> Result<(), Box<dyn Error>> {
+        let collection = match self.new_collection(upsert_collection)? {
+            Some(collection) => collection,
+            None => return Ok(()),
+        };
+        let _wal = self.log_event(Event::UpsertCollection(upsert_collection.clone()))?;
+        self.insert_collection(collection)
     }
 
-    pub fn remove_collection(&self, remove_collection: &RemoveCollection) {
-        let mut collections = self.collections.write().unwrap();
-        collections.remove(&remove_collection.name.clone());
+    pub fn remove_collection(
+        &self,
+        remove_collection: &RemoveCollection,
+    ) -> Result<(), Box<dyn Error>> {
+        let _wal = self.log_event(Event::RemoveCollection(remove_collection.clone()))?;
+        self.apply_remove_collection(remove_collection)
     }
 
+    /// Fails without logging anything if the collection cannot index the
+    /// source, see `Collection::check_source`.
     pub fn add_image(&self, add_image: AddImage) -> Result<(), Box<dyn Error>> {
+        {
+            let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+            if let Some(collection) = collections.get(&add_image.collection_name) {
+                collection.check_source(&add_image.source)?;
+            }
+        }
+        let _wal = self.log_event(Event::AddImage(add_image.clone()))?;
         self.job_queue.add_work(Job::AddImage(add_image));
         Ok(())
     }
 
-    pub fn remove_image(&self, remove_image: RemoveImage) -> Result<(), Box<dyn Error>> {
+    /// Also cancels the jobs adding this image which are queued or running.
+    /// Returns false without logging anything if the collection has no image
+    /// with this id and none is being added.
+    pub fn remove_image(&self, remove_image: RemoveImage) -> Result<bool, Box<dyn Error>> {
+        let mut wal = self.lock_wal()?;
+        let cancelled = self.cancel_add_image(&remove_image.collection_name, &remove_image.id)?;
+        if !cancelled && !self.contains_image(&remove_image.collection_name, &remove_image.id)? {
+            return Ok(false);
+        }
+        if let Some(wal) = wal.as_mut() {
+            wal.append(&Event::RemoveImage(remove_image.clone()))?;
+        }
+        self.apply_remove_image(remove_image)?;
+        Ok(true)
+    }
+
+    /// Drops the queued and running jobs adding `id` to the collection, a
+    /// running job then discards its features. Returns false if there was
+    /// none.
+    fn cancel_add_image(&self, collection_name: &str, id: &str) -> Result<bool, Box<dyn Error>> {
+        let adds_image = |job: &Job| match job {
+            Job::AddImage(add_image) => {
+                add_image.collection_name == collection_name && add_image.id == id
+            }
+            _ => false,
+        };
+        let mut in_progress = self.in_progress.lock().map_err(|_| "Mutex Error")?;
+        let mut queue = self.job_queue.inner.lock().map_err(|_| "Mutex Error")?;
+        let mut cancelled = self.cancelled.lock().map_err(|_| "Mutex Error")?;
+        let n_queued = queue.len();
+        queue.retain(|job| !adds_image(job));
+        let running: Vec<Job> = in_progress
+            .iter()
+            .filter(|job| adds_image(job))
+            .cloned()
+            .collect();
+        in_progress.retain(|job| !adds_image(job));
+        let found = !running.is_empty() || queue.len() < n_queued;
+        cancelled.extend(running);
+        Ok(found)
+    }
+
+    fn contains_image(&self, collection_name: &str, id: &str) -> Result<bool, Box<dyn Error>> {
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+        Ok(collections
+            .get(collection_name)
+            .is_some_and(|c| c.index.contains(id)))
+    }
+
+    fn apply_upsert_collection(
+        &self,
+        upsert_collection: &UpsertCollection,
+    ) -> Result<(), Box<dyn Error>> {
+        match self.new_collection(upsert_collection)? {
+            Some(collection) => self.insert_collection(collection),
+            None => Ok(()),
+        }
+    }
+
+    /// Creates the collection and loads its model without locking the
+    /// collections. Returns `None` if the collection already exists.
+    fn new_collection(
+        &self,
+        upsert_collection: &UpsertCollection,
+    ) -> Result<Option<Collection>, Box<dyn Error>> {
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+        if collections.contains_key(&upsert_collection.name) {
+            // create a task to rebuild a collection
+            // I think for now we can disable this
+            return Ok(None);
+        }
+        drop(collections);
+        let collection = Collection::new(
+            &upsert_collection.name,
+            &upsert_collection.config,
+            &upsert_collection.index_config,
+        );
+        collection.check_config()?;
+        Ok(Some(collection))
+    }
+
+    fn insert_collection(&self, collection: Collection) -> Result<(), Box<dyn Error>> {
         let mut collections = self.collections.write().map_err(|_| "RwLock Error")?;
+        // a concurrent upsert may have created it in the meantime
         collections
-            .entry(remove_image.collection_name.clone())
-            .and_modify(|c| c.index.remove(remove_image.id));
+            .entry(collection.name.clone())
+            .or_insert(collection);
+        Ok(())
+    }
+
+    fn apply_remove_collection(
+        &self,
+        remove_collection: &RemoveCollection,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut collections = self.collections.write().map_err(|_| "RwLock Error")?;
+        collections.remove(&remove_collection.name.clone());
+        self.duplicates
+            .write()
+            .map_err(|_| "RwLock Error")?
+            .remove(&remove_collection.name);
+        self.clusters
+            .write()
+            .map_err(|_| "RwLock Error")?
+            .remove(&remove_collection.name);
         Ok(())
     }
 
+    /// Queues a job finding the near duplicates of a collection, replacing
+    /// its previous report. Returns false if the collection is unknown.
+    pub fn find_duplicates(&self, find_duplicates: FindDuplicates) -> Result<bool, Box<dyn Error>> {
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+        if !collections.contains_key(&find_duplicates.collection_name) {
+            return Ok(false);
+        }
+        self.duplicates.write().map_err(|_| "RwLock Error")?.insert(
+            find_duplicates.collection_name.clone(),
+            DuplicateReport::queued(find_duplicates.clone()),
+        );
+        self.job_queue
+            .add_work(Job::FindDuplicates(find_duplicates));
+        Ok(true)
+    }
+
+    pub fn duplicate_report(
+        &self,
+        get_duplicates: &GetDuplicates,
+    ) -> Result<Option<DuplicateReport>, Box<dyn Error>> {
+        let duplicates = self.duplicates.read().map_err(|_| "RwLock Error")?;
+        Ok(duplicates.get(&get_duplicates.collection_name).cloned())
+    }
+
+    /// Queues a job fitting the PCA of a collection on `sample`. Returns false
+    /// without logging anything if the collection is unknown, fails if it has
+    /// no PCA to fit.
+    pub fn fit_pca(&self, fit_pca: FitPca) -> Result<bool, Box<dyn Error>> {
+        {
+            let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+            let collection = match collections.get(&fit_pca.collection_name) {
+                Some(collection) => collection,
+                None => return Ok(false),
+            };
+            let config = collection
+                .index_config
+                .pca
+                .ok_or_else(|| format!("Collection {} has no PCA", collection.name))?;
+            if collection.pca.is_some() {
+                return Err(format!(
+                    "The PCA of collection {} is already fitted",
+                    collection.name
+                )
+                .into());
+            }
+            if fit_pca.sample.len() < config.dimension {
+                return Err(format!(
+                    "Fitting a PCA to {} dimensions needs at least {} vectors",
+                    config.dimension, config.dimension
+                )
+                .into());
+            }
+            for source in &fit_pca.sample {
+                collection.check_source(source)?;
+            }
+        }
+        let _wal = self.log_event(Event::FitPca(fit_pca.clone()))?;
+        self.job_queue.add_work(Job::FitPca(fit_pca));
+        Ok(true)
+    }
+
+    /// Queues a k-means job over the images of a collection, replacing its
+    /// previous clustering. Returns false if the collection is unknown.
+    pub fn cluster_collection(
+        &self,
+        cluster_collection: ClusterCollection,
+    ) -> Result<bool, Box<dyn Error>> {
+        if cluster_collection.k == 0 {
+            return Err("k must be at least 1".into());
+        }
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+        if !collections.contains_key(&cluster_collection.collection_name) {
+            return Ok(false);
+        }
+        self.clusters.write().map_err(|_| "RwLock Error")?.insert(
+            cluster_collection.collection_name.clone(),
+            ClusterReport::queued(cluster_collection.clone()),
+        );
+        self.job_queue
+            .add_work(Job::ClusterCollection(cluster_collection));
+        Ok(true)
+    }
+
+    /// Size, centroid and most central images of every cluster, largest
+    /// cluster first. Returns `None` if the collection was not clustered.
+    pub fn clusters(
+        &self,
+        get_clusters: &GetClusters,
+    ) -> Result<Option<ClustersResult>, Box<dyn Error>> {
+        let clusters = self.clusters.read().map_err(|_| "RwLock Error")?;
+        Ok(clusters
+            .get(&get_clusters.collection_name)
+            .map(|report| ClustersResult {
+                collection_name: report.request.collection_name.clone(),
+                status: report.status.clone(),
+                clusters: report
+                    .results
+                    .iter()
+                    .map(|cluster| ClusterOverview {
+                        centroid: cluster.centroid.clone(),
+                        size: cluster.members.len(),
+                        central: cluster
+                            .members
+                            .iter()
+                            .take(get_clusters.n_central)
+                            .cloned()
+                            .collect(),
+                    })
+                    .collect(),
+            }))
+    }
+
+    /// Returns `None` if the collection was not clustered or has no such
+    /// cluster.
+    pub fn cluster_members(
+        &self,
+        get_cluster_members: &GetClusterMembers,
+    ) -> Result<Option<Vec<ClusterMember>>, Box<dyn Error>> {
+        let clusters = self.clusters.read().map_err(|_| "RwLock Error")?;
+        Ok(clusters
+            .get(&get_cluster_members.collection_name)
+            .and_then(|report| report.results.get(get_cluster_members.cluster))
+            .map(|cluster| cluster.members.clone()))
+    }
+
+    fn apply_remove_image(&self, remove_image: RemoveImage) -> Result<bool, Box<dyn Error>> {
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+        match collections.get(&remove_image.collection_name) {
+            Some(collection) => collection.remove_image(&remove_image.id),
+            None => Ok(false),
+        }
+    }
+
+    /// Appends the event to the write-ahead log (if there is one). The returned
+    /// guard keeps the log locked until the caller has applied the event so a
+    /// snapshot can never be taken between logging and applying it.
+    fn log_event(
+        &self,
+        event: Event,
+    ) -> Result<Option<MutexGuard<'_, WriteAheadLog>>, Box<dyn Error>> {
+        let mut wal = self.lock_wal()?;
+        if let Some(wal) = wal.as_mut() {
+            wal.append(&event)?;
+        }
+        Ok(wal)
+    }
+
+    fn lock_wal(&self) -> Result<Option<MutexGuard<'_, WriteAheadLog>>, Box<dyn Error>> {
+        match &self.wal {
+            Some(wal) => Ok(Some(wal.lock().map_err(|_| "Mutex Error")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Images given by id are not part of the results.
     pub fn search_image(&self, search_image: SearchImage) -> Result<ImageResult, Box<dyn Error>> {
-        let collections = self.collections.write().map_err(|_| "RwLock Error")?;
+        let source = search_image.source.clone().map(|source| WeightedQuery {
+            query: QuerySource::Source(source),
+            weight: 1.0,
+        });
+        let negative = search_image.negative.iter().map(|example| WeightedQuery {
+            query: example.query.clone(),
+            weight: -example.weight,
+        });
+        let examples: Vec<WeightedQuery> = source
+            .into_iter()
+            .chain(search_image.positive.iter().cloned())
+            .chain(negative)
+            .collect();
+        let positive_weight: f64 = examples.iter().map(|e| e.weight.max(0.0)).sum();
+        if positive_weight <= 0.0 {
+            return Err("Search needs a source or positive examples".into());
+        }
+        let exclude: Vec<&str> = examples.iter().filter_map(|e| e.query.id()).collect();
+        // download the images before locking the collections
+        let inputs = parallel_map(examples.clone(), |example| {
+            EmbeddingApp::load_query(example.query).map_err(|e| e.to_string())
+        });
+
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
         if let Some(collection) = collections.get(&search_image.collection_name) {
-            let image = EmbeddingApp::image_source_to_rgb_image(&search_image.source)?;
-            println!("Extracting features");
-            let features = collection.model.extract_features(image)?;
-            println!("Features len {}", features.len());
-            let results: Vec<_> = collection
-                .index
-                .search(&features)
+            let mut features = vec![];
+            for (example, extracted) in examples
                 .iter()
-                .map(|result| SingleImageResult {
-                    id: result.id.clone(),
-                    similarity: result.distance,
-                })
-                .collect();
+                .zip(collection.extract_features_batch(inputs))
+            {
+                features.push((extracted?, example.weight));
+            }
+            let features =
+                combine_examples(collection.index_config.metric(), features, positive_weight)?;
+            println!("Features len {}", features.len());
+            let diversify = search_image.diversify.as_ref();
+            let results = EmbeddingApp::search_collection(
+                collection,
+                &features,
+                diversify.map_or(search_image.n_results, |mmr| {
+                    mmr.n_candidates(search_image.n_results)
+                }),
+                search_image.ef,
+                search_image.filter.as_ref(),
+                &exclude,
+                search_image.rerank.as_ref(),
+            )?;
+            let results = match diversify {
+                Some(mmr) => collection.diversify(results, mmr, search_image.n_results),
+                None => results,
+            };
             Ok(ImageResult {
                 collection_name: search_image.collection_name.clone(),
                 results,
@@ -143,23 +1003,471 @@ impl EmbeddingApp {
         }
     }
 
+    /// Neighbours of an image which is already in the collection, without the
+    /// image itself. Returns `None` if the collection or the id is unknown.
+    pub fn search_similar(
+        &self,
+        search_similar: SearchSimilar,
+    ) -> Result<Option<ImageResult>, Box<dyn Error>> {
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+        let collection = match collections.get(&search_similar.collection_name) {
+            Some(collection) => collection,
+            None => return Ok(None),
+        };
+        let features = match collection.index.vector(&search_similar.id) {
+            Some(features) => features,
+            None => return Ok(None),
+        };
+        let diversify = search_similar.diversify.as_ref();
+        let results = EmbeddingApp::search_collection(
+            collection,
+            &features,
+            diversify.map_or(search_similar.n_results, |mmr| {
+                mmr.n_candidates(search_similar.n_results)
+            }),
+            search_similar.ef,
+            search_similar.filter.as_ref(),
+            &[&search_similar.id],
+            search_similar.rerank.as_ref(),
+        )?;
+        let results = match diversify {
+            Some(mmr) => collection.diversify(results, mmr, search_similar.n_results),
+            None => results,
+        };
+        Ok(Some(ImageResult {
+            collection_name: search_similar.collection_name.clone(),
+            results,
+        }))
+    }
+
+    /// Searches every collection and merges the results by distance. All
+    /// collections must exist, share the metric and embed images with the
+    /// same model without a PCA; the query is embedded once per distinct
+    /// model config.
+    pub fn federated_search(
+        &self,
+        federated_search: FederatedSearch,
+    ) -> Result<FederatedResult, Box<dyn Error>> {
+        let input = EmbeddingApp::load_source(&federated_search.source)?;
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+        let mut searched: Vec<&Collection> = Vec::new();
+        for name in &federated_search.collection_names {
+            let collection = collections
+                .get(name)
+                .ok_or_else(|| format!("Unknown collection {}", name))?;
+            if !searched.iter().any(|c| c.name == collection.name) {
+                searched.push(collection);
+            }
+        }
+        if let Some((first, rest)) = searched.split_first() {
+            let projected = searched.iter().find(|c| c.index_config.pca.is_some());
+            if let (Some(collection), false) = (projected, rest.is_empty()) {
+                return Err(format!(
+                    "Collection {} projects its vectors with a PCA of its own, its distances \
+                     cannot be compared with other collections",
+                    collection.name
+                )
+                .into());
+            }
+            for collection in rest {
+                if collection.index_config.metric() != first.index_config.metric() {
+                    return Err(format!(
+                        "Collections {} and {} use different metrics: {:?} and {:?}",
+                        first.name,
+                        collection.name,
+                        first.index_config.metric(),
+                        collection.index_config.metric()
+                    )
+                    .into());
+                }
+                if collection.embedding_space() != first.embedding_space() {
+                    return Err(format!(
+                        "Collections {} and {} use incompatible models: {:?} and {:?}",
+                        first.name,
+                        collection.name,
+                        first.embedding_space(),
+                        collection.embedding_space()
+                    )
+                    .into());
+                }
+            }
+        }
+
+        // features by serialized model config
+        let mut features: HashMap<String, Vec<f64>> = HashMap::new();
+        let mut results = Vec::new();
+        for collection in searched {
+            let model_config = serde_json::to_string(&collection.model_config)?;
+            if !features.contains_key(&model_config) {
+                let extracted = collection.extract_features(input.clone())?;
+                features.insert(model_config.clone(), extracted);
+            }
+            let found = EmbeddingApp::search_collection(
+                collection,
+                &features[&model_config],
+                federated_search.n_results,
+                federated_search.ef,
+                federated_search.filter.as_ref(),
+                &[],
+                None,
+            )?;
+            results.extend(found.into_iter().map(|result| FederatedImageResult {
+                collection_name: collection.name.clone(),
+                id: result.id,
+                distance: result.distance,
+                similarity: result.similarity,
+            }));
+        }
+        results.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        results.truncate(federated_search.n_results);
+        Ok(FederatedResult { results })
+    }
+
+    /// All images within `radius` of the query, closest first and at most
+    /// `max_results` (capped at `MAX_RANGE_RESULTS`) of them. Returns `None`
+    /// if the collection or the id of the query is unknown.
+    pub fn range_search(
+        &self,
+        range_search: RangeSearch,
+    ) -> Result<Option<ImageResult>, Box<dyn Error>> {
+        let input = EmbeddingApp::load_query(range_search.query.clone())?;
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+        let collection = match collections.get(&range_search.collection_name) {
+            Some(collection) => collection,
+            None => return Ok(None),
+        };
+        let exclude: Vec<&str> = range_search.query.id().into_iter().collect();
+        if exclude.iter().any(|id| !collection.index.contains(id)) {
+            return Ok(None);
+        }
+        let features = collection.extract_features(input)?;
+        let max_results = range_search
+            .max_results
+            .unwrap_or(MAX_RANGE_RESULTS)
+            .min(MAX_RANGE_RESULTS);
+        let ef = range_search.ef.unwrap_or(DEFAULT_EF);
+        let neighbors = EmbeddingApp::with_accept(
+            collection,
+            range_search.filter.as_ref(),
+            &exclude,
+            |accept| {
+                collection.index.search_range(
+                    &features,
+                    range_search.radius,
+                    max_results,
+                    ef,
+                    accept.unwrap_or(&|_| true),
+                )
+            },
+        )?;
+        Ok(Some(ImageResult {
+            collection_name: range_search.collection_name.clone(),
+            results: EmbeddingApp::to_results(neighbors),
+        }))
+    }
+
+    /// Runs the queries in parallel, the results are in the order of the
+    /// queries with an error for every query which failed. Returns `None` if
+    /// the collection is unknown.
+    pub fn batch_search(
+        &self,
+        batch_search: BatchSearch,
+    ) -> Result<Option<BatchSearchResult>, Box<dyn Error>> {
+        let BatchSearch {
+            collection_name,
+            queries,
+            n_results,
+            ef,
+            filter,
+            rerank,
+        } = batch_search;
+        // download the images before locking the collections
+        let inputs = parallel_map(queries.clone(), |query| {
+            EmbeddingApp::load_query(query).map_err(|e| e.to_string())
+        });
+
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+        let collection = match collections.get(&collection_name) {
+            Some(collection) => collection,
+            None => return Ok(None),
+        };
+        let features = collection.extract_features_batch(inputs);
+
+        let searches: Vec<_> = queries.iter().zip(features).collect();
+        let results = parallel_map(searches, |(query, features)| {
+            let exclude: Vec<&str> = query.id().into_iter().collect();
+            let results = features.and_then(|features| {
+                EmbeddingApp::search_collection(
+                    collection,
+                    &features,
+                    n_results,
+                    ef,
+                    filter.as_ref(),
+                    &exclude,
+                    rerank.as_ref(),
+                )
+                .map_err(|e| e.to_string())
+            });
+            match results {
+                Ok(results) => BatchItemResult {
+                    results,
+                    error: None,
+                },
+                Err(error) => BatchItemResult {
+                    results: vec![],
+                    error: Some(error),
+                },
+            }
+        });
+        Ok(Some(BatchSearchResult {
+            collection_name,
+            results,
+        }))
+    }
+
+    /// Nearest `n_results` neighbors of `features`, with `options` more
+    /// candidates are fetched and re-ranked by their exact distance.
+    fn search_collection(
+        collection: &Collection,
+        features: &[f64],
+        n_results: usize,
+        ef: Option<usize>,
+        filter: Option<&Filter>,
+        exclude: &[&str],
+        options: Option<&Rerank>,
+    ) -> Result<Vec<SingleImageResult>, Box<dyn Error>> {
+        if options.is_some() && !collection.index_config.full_vectors {
+            return Err(format!(
+                "Collection {} does not keep full vectors to re-rank, create it with \
+                 full_vectors in its index_config",
+                collection.name
+            )
+            .into());
+        }
+        let ef = ef.unwrap_or(DEFAULT_EF);
+        let n_candidates = options.map_or(n_results, |options| options.n_candidates(n_results));
+        let neighbors =
+            EmbeddingApp::with_accept(collection, filter, exclude, |accept| match accept {
+                Some(accept) => {
+                    collection
+                        .index
+                        .search_filtered(features, n_candidates, ef, accept)
+                }
+                None => collection.index.search(features, n_candidates, ef),
+            })?;
+        let neighbors = match options {
+            Some(_) => rerank(
+                &collection.index,
+                collection.index_config.metric(),
+                features,
+                neighbors,
+                n_results,
+            ),
+            None => neighbors,
+        };
+        Ok(EmbeddingApp::to_results(neighbors))
+    }
+
+    /// Calls `search` with a function accepting the ids whose metadata
+    /// matches `filter` except `exclude`, or with `None` if all ids are accepted.
+    fn with_accept<T>(
+        collection: &Collection,
+        filter: Option<&Filter>,
+        exclude: &[&str],
+        search: impl FnOnce(Option<&(dyn Fn(&str) -> bool + Sync)>) -> T,
+    ) -> Result<T, Box<dyn Error>> {
+        if filter.is_none() && exclude.is_empty() {
+            return Ok(search(None));
+        }
+        let metadata = collection.metadata.read().map_err(|_| "RwLock Error")?;
+        let no_metadata = Metadata::new();
+        let accept = |id: &str| {
+            !exclude.contains(&id)
+                && filter
+                    .is_none_or(|filter| filter.matches(metadata.get(id).unwrap_or(&no_metadata)))
+        };
+        Ok(search(Some(&accept)))
+    }
+
+    fn to_results(neighbors: Vec<AnnNeighbor>) -> Vec<SingleImageResult> {
+        neighbors
+            .into_iter()
+            .map(|result| SingleImageResult {
+                id: result.id,
+                distance: result.distance,
+                similarity: result.similarity,
+            })
+            .collect()
+    }
+
+    /// Restores the last snapshot, replays the events logged after it and
+    /// starts logging new events to `data_dir`.
+    pub fn recover(&mut self, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let loaded = read_snapshot(data_dir)?;
+        {
+            let mut collections = self.collections.write().map_err(|_| "RwLock Error")?;
+            for collection in loaded {
+                println!(
+                    "Restored collection {} with {} images",
+                    collection.name,
+                    collection.index.len()
+                );
+                collections.insert(collection.name.clone(), collection);
+            }
+        }
+
+        let events = read_events(data_dir)?;
+        println!("Replaying {} events", events.len());
+        // images whose job ran are indexed from the logged result, images
+        // removed before their job ran are dropped, the others are queued again
+        let mut last_settled = HashMap::new();
+        for (position, event) in events.iter().enumerate() {
+            let key = match event {
+                Event::ImageIndexed(image_indexed) => {
+                    (&image_indexed.collection_name, &image_indexed.id)
+                }
+                Event::RemoveImage(remove_image) => {
+                    (&remove_image.collection_name, &remove_image.id)
+                }
+                _ => continue,
+            };
+            last_settled.insert(key, position);
+        }
+        let ran: Vec<bool> = events
+            .iter()
+            .enumerate()
+            .map(|(position, event)| match event {
+                Event::AddImage(add_image)
+                | Event::UploadQueued(UploadQueued { add_image, .. }) => last_settled
+                    .get(&(&add_image.collection_name, &add_image.id))
+                    .is_some_and(|&settled| settled > position),
+                _ => false,
+            })
+            .collect();
+        for (event, ran) in events.into_iter().zip(ran) {
+            if ran {
+                continue;
+            }
+            // only the uploads queued again are read back
+            let event = match event {
+                Event::UploadQueued(upload_queued) => {
+                    let id = upload_queued.add_image.id.clone();
+                    match read_upload(data_dir, upload_queued) {
+                        Ok(add_image) => Event::AddImage(add_image),
+                        Err(e) => {
+                            println!("Cannot read the upload of image {}: {}", id, e);
+                            continue;
+                        }
+                    }
+                }
+                event => event,
+            };
+            self.apply_event(event)?;
+        }
+
+        self.wal = Some(Arc::new(Mutex::new(WriteAheadLog::open(data_dir)?)));
+        Ok(())
+    }
+
+    fn apply_event(&self, event: Event) -> Result<(), Box<dyn Error>> {
+        match event {
+            Event::AddImage(add_image) => {
+                self.job_queue.add_work(Job::AddImage(add_image));
+                Ok(())
+            }
+            Event::ImageIndexed(image_indexed) => {
+                if self.apply_image_indexed(&image_indexed)? {
+                    EmbeddingApp::fit_collection_pca(
+                        self.collections.clone(),
+                        &image_indexed.collection_name,
+                        None,
+                    )?;
+                }
+                Ok(())
+            }
+            Event::RemoveImage(remove_image) => {
+                self.apply_remove_image(remove_image)?;
+                Ok(())
+            }
+            Event::UpsertCollection(upsert_collection) => {
+                self.apply_upsert_collection(&upsert_collection)
+            }
+            Event::RemoveCollection(remove_collection) => {
+                self.apply_remove_collection(&remove_collection)
+            }
+            Event::FitPca(fit_pca) => {
+                if let Err(e) =
+                    EmbeddingApp::fit_pca_in_collection(self.collections.clone(), &fit_pca)
+                {
+                    println!("Cannot fit PCA of {}: {}", fit_pca.collection_name, e);
+                }
+                Ok(())
+            }
+            Event::SearchImage(_) => Ok(()),
+            Event::UploadQueued(_) => Err("Uploads are read back by recover".into()),
+        }
+    }
+
+    /// Writes a snapshot of all collections and drops the events it covers.
+    ///
+    /// The log is only locked to rotate it: the new log starts with the jobs
+    /// which are still queued or being indexed, they are not part of the
+    /// snapshot. Requests go on while the snapshot is written, so their events
+    /// are both in the new log and maybe in the snapshot. Replaying them again
+    /// is harmless as every event sets or removes what it changes. The previous
+    /// log is kept until the snapshot is written.
+    pub fn save_snapshot(&self, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let _snapshot = self.snapshot_lock.lock().map_err(|_| "Mutex Error")?;
+        if let Some(mut wal) = self.lock_wal()? {
+            wal.rotate(&self.pending_events()?)?;
+        }
+
+        {
+            let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+            write_snapshot(data_dir, &collections)?;
+        }
+
+        if let Some(mut wal) = self.lock_wal()? {
+            wal.drop_previous()?;
+        }
+        Ok(())
+    }
+
+    /// Events of the jobs which have not logged their result yet.
+    fn pending_events(&self) -> Result<Vec<Event>, Box<dyn Error>> {
+        let in_progress = self.in_progress.lock().map_err(|_| "Mutex Error")?;
+        let queue = self.job_queue.inner.lock().map_err(|_| "Mutex Error")?;
+        Ok(in_progress
+            .iter()
+            .chain(queue.iter())
+            .filter_map(|job| match job {
+                Job::AddImage(add_image) => Some(Event::AddImage(add_image.clone())),
+                Job::FitPca(fit_pca) => Some(Event::FitPca(fit_pca.clone())),
+                // reports are not persisted
+                Job::FindDuplicates(_) | Job::ClusterCollection(_) => None,
+            })
+            .collect())
+    }
+
+    pub fn start_snapshots(&self, data_dir: PathBuf, interval: Duration) -> JoinHandle<()> {
+        println!("Saving snapshots to {:?} every {:?}", data_dir, interval);
+        let app = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = app.save_snapshot(&data_dir) {
+                println!("Cannot save snapshot: {}", e);
+            }
+        })
+    }
+
     pub fn start_workers(&self) -> Vec<JoinHandle<()>> {
         println!("Starting workers");
         let mut handles: Vec<_> = Vec::new();
         for n in 0..self.n_workers {
             println!("Starting worker {}", n);
-            let tq = self.job_queue.clone();
-            let collections = self.collections.clone();
+            let app = self.clone();
             let handle = thread::spawn(move || loop {
-                if let Some(job) = tq.get_work() {
-                    println!("Queue length {}", tq.inner.lock().unwrap().len());
-                    match job {
-                        Job::AddImage(add_image) => {
-                            EmbeddingApp::add_image_to_collection(collections.clone(), &add_image);
-                            ()
-                        }
-                    }
-                }
+                app.run_next_job();
                 std::thread::yield_now();
                 std::thread::sleep(std::time::Duration::from_millis(10));
             });
@@ -168,56 +1476,361 @@ impl EmbeddingApp {
         handles
     }
 
-    fn add_image_to_collection(
+    /// Takes the next job from the queue and runs it. Returns false if the
+    /// queue is empty.
+    fn run_next_job(&self) -> bool {
+        let job = {
+            let mut in_progress = self.in_progress.lock().unwrap();
+            let job = self.job_queue.get_work();
+            if let Some(job) = &job {
+                in_progress.push(job.clone());
+            }
+            job
+        };
+        let job = match job {
+            Some(job) => job,
+            None => return false,
+        };
+        println!(
+            "Queue length {}",
+            self.job_queue.inner.lock().unwrap().len()
+        );
+        match &job {
+            Job::AddImage(add_image) => {
+                if let Err(e) = self.add_image_to_collection(add_image) {
+                    println!("Cannot add image {}: {}", add_image.id, e);
+                }
+            }
+            Job::FindDuplicates(find_duplicates) => {
+                if let Err(e) = EmbeddingApp::find_duplicates_in_collection(
+                    self.collections.clone(),
+                    self.duplicates.clone(),
+                    find_duplicates,
+                ) {
+                    println!(
+                        "Cannot find duplicates in {}: {}",
+                        find_duplicates.collection_name, e
+                    );
+                }
+            }
+            Job::FitPca(fit_pca) => {
+                if let Err(e) =
+                    EmbeddingApp::fit_pca_in_collection(self.collections.clone(), fit_pca)
+                {
+                    println!("Cannot fit PCA of {}: {}", fit_pca.collection_name, e);
+                }
+            }
+            Job::ClusterCollection(cluster_collection) => {
+                if let Err(e) = EmbeddingApp::cluster_images_in_collection(
+                    self.collections.clone(),
+                    self.clusters.clone(),
+                    cluster_collection,
+                ) {
+                    println!(
+                        "Cannot cluster {}: {}",
+                        cluster_collection.collection_name, e
+                    );
+                }
+            }
+        }
+        // AddImage jobs have already left when their result was logged
+        self.in_progress.lock().unwrap().retain(|j| j != &job);
+        true
+    }
+
+    /// Embeds the image without holding the log, then logs the features (or
+    /// the failure), indexes them and takes the job out of `in_progress` while
+    /// holding it. A snapshot sees either the queued job or its result.
+    fn add_image_to_collection(&self, add_image: &AddImage) -> Result<(), Box<dyn Error>> {
+        let (features, error) = match self.embed_image(add_image) {
+            Ok(features) => (Some(features), None),
+            Err(e) => (None, Some(e)),
+        };
+        let image_indexed = ImageIndexed {
+            collection_name: add_image.collection_name.clone(),
+            id: add_image.id.clone(),
+            features,
+            metadata: add_image.metadata.clone(),
+        };
+        let pca_due = {
+            let mut wal = self.lock_wal()?;
+            let job = Job::AddImage(add_image.clone());
+            let mut cancelled = self.cancelled.lock().map_err(|_| "Mutex Error")?;
+            if let Some(position) = cancelled.iter().position(|j| j == &job) {
+                cancelled.remove(position);
+                println!("Image {} was removed while it was added", add_image.id);
+                return Ok(());
+            }
+            drop(cancelled);
+            if let Some(wal) = wal.as_mut() {
+                wal.append(&Event::ImageIndexed(image_indexed.clone()))?;
+            }
+            let pca_due = self.apply_image_indexed(&image_indexed);
+            self.in_progress
+                .lock()
+                .map_err(|_| "Mutex Error")?
+                .retain(|j| j != &job);
+            pca_due?
+        };
+        if let Some(error) = error {
+            return Err(error);
+        }
+        if pca_due {
+            EmbeddingApp::fit_collection_pca(
+                self.collections.clone(),
+                &add_image.collection_name,
+                None,
+            )?;
+        }
+        println!("Finished");
+        Ok(())
+    }
+
+    fn embed_image(&self, add_image: &AddImage) -> Result<Vec<f64>, Box<dyn Error>> {
+        let input = EmbeddingApp::load_source(&add_image.source)?;
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+        match collections.get(&add_image.collection_name) {
+            Some(collection) => collection.embed(input),
+            None => Err(format!("Unknown collection {}", add_image.collection_name).into()),
+        }
+    }
+
+    /// Indexes the features with their metadata. Returns true once the
+    /// collection has enough images to fit its PCA.
+    fn apply_image_indexed(&self, image_indexed: &ImageIndexed) -> Result<bool, Box<dyn Error>> {
+        let features = match &image_indexed.features {
+            Some(features) => features.clone(),
+            None => return Ok(false),
+        };
+        // the index has locks of its own, the collections are only read
+        let collections = self.collections.read().map_err(|_| "RwLock Error")?;
+        let collection = match collections.get(&image_indexed.collection_name) {
+            Some(collection) => collection,
+            None => return Ok(false),
+        };
+        println!("Writing features to the index");
+        let pca_due = collection.add_features(features, image_indexed.id.clone())?;
+        let mut metadata = collection.metadata.write().map_err(|_| "RwLock Error")?;
+        if image_indexed.metadata.is_empty() {
+            metadata.remove(&image_indexed.id);
+        } else {
+            metadata.insert(image_indexed.id.clone(), image_indexed.metadata.clone());
+        }
+        Ok(pca_due)
+    }
+
+    fn fit_pca_in_collection(
         collections: Arc<RwLock<HashMap<String, Collection>>>,
-        add_image: &AddImage,
+        fit_pca: &FitPca,
     ) -> Result<(), Box<dyn Error>> {
-        let image = EmbeddingApp::image_source_to_rgb_image(&add_image.source)?;
+        // download the images before locking the collections
+        let inputs = parallel_map(fit_pca.sample.clone(), |source| {
+            EmbeddingApp::load_source(&source).map_err(|e| e.to_string())
+        });
+        let sample = {
+            let collections = collections.read().map_err(|_| "RwLock Error")?;
+            let collection = collections
+                .get(&fit_pca.collection_name)
+                .ok_or("Unknown collection")?;
+            parallel_map(inputs, |input| {
+                input.and_then(|input| collection.embed(input).map_err(|e| e.to_string()))
+            })
+            .into_iter()
+            .collect::<Result<Vec<_>, String>>()?
+        };
+        EmbeddingApp::fit_collection_pca(collections, &fit_pca.collection_name, Some(sample))
+    }
 
-        println!("Locking collections for reading");
-        let collection_read = collections.read().map_err(|_| "RwLock Error")?;
+    /// Fits the PCA of a collection on `sample`, or on its stored vectors, and
+    /// re-indexes them projected. The collections are only locked to copy the
+    /// vectors and to swap in the new index.
+    fn fit_collection_pca(
+        collections: Arc<RwLock<HashMap<String, Collection>>>,
+        collection_name: &str,
+        sample: Option<Vec<Vec<f64>>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let fit = {
+            let collections = collections.read().map_err(|_| "RwLock Error")?;
+            let collection = collections
+                .get(collection_name)
+                .ok_or("Unknown collection")?;
+            let config = collection
+                .index_config
+                .pca
+                .ok_or_else(|| format!("Collection {} has no PCA", collection_name))?;
+            if let Some(sample) = &sample {
+                if sample.len() < config.dimension {
+                    return Err(format!(
+                        "Fitting a PCA to {} dimensions needs at least {} vectors, got {}",
+                        config.dimension,
+                        config.dimension,
+                        sample.len()
+                    )
+                    .into());
+                }
+            }
+            collection.begin_fit_pca()?
+        };
+        let fit = match fit {
+            Some(fit) => fit,
+            None => {
+                println!(
+                    "The PCA of {} is already fitted or being fitted",
+                    collection_name
+                );
+                return Ok(());
+            }
+        };
+        let fitted = fit.fit(sample);
+        let mut collections = collections.write().map_err(|_| "RwLock Error")?;
+        match collections.get_mut(collection_name) {
+            Some(collection) => collection.finish_fit_pca(fitted),
+            None => Ok(()),
+        }
+    }
 
-        println!("Extracting features");
-        let features = if let Some(collection) = collection_read.get(&add_image.collection_name) {
-            let features = collection.model.extract_features(image)?;
-            Some(features)
-        } else {
-            None
+    fn find_duplicates_in_collection(
+        collections: Arc<RwLock<HashMap<String, Collection>>>,
+        duplicates: JobReports<FindDuplicates, DuplicateGroup>,
+        find_duplicates: &FindDuplicates,
+    ) -> Result<(), Box<dyn Error>> {
+        let set_status = |status: JobStatus, groups: Vec<DuplicateGroup>| {
+            update_report(
+                &duplicates,
+                &find_duplicates.collection_name,
+                find_duplicates,
+                status,
+                groups,
+            )
+        };
+        // the index is shared, the collections are not locked during the search
+        let index = {
+            let collections = collections.read().map_err(|_| "RwLock Error")?;
+            collections
+                .get(&find_duplicates.collection_name)
+                .map(|collection| collection.index.clone())
         };
-        drop(collection_read);
+        let index = match index {
+            Some(index) => index,
+            None => {
+                return set_status(JobStatus::Failed("Unknown collection".into()), vec![]);
+            }
+        };
+        set_status(JobStatus::Running, vec![])?;
+        println!("Finding duplicates in {}", find_duplicates.collection_name);
+        let groups = find_duplicates_in_index(&index, find_duplicates.threshold);
+        println!("Found {} groups of duplicates", groups.len());
+        set_status(JobStatus::Done, groups)
+    }
 
-        println!("Writing features to the index");
-        if let Some(features) = features {
-            let collection_write = collections.write().map_err(|_| "RwLock Error")?;
-            if let Some(collection) = collection_write.get(&add_image.collection_name) {
-                collection.index.insert(features, add_image.id.clone());
+    fn cluster_images_in_collection(
+        collections: Arc<RwLock<HashMap<String, Collection>>>,
+        clusters: JobReports<ClusterCollection, Cluster>,
+        cluster_collection: &ClusterCollection,
+    ) -> Result<(), Box<dyn Error>> {
+        let set_status = |status: JobStatus, found: Vec<Cluster>| {
+            update_report(
+                &clusters,
+                &cluster_collection.collection_name,
+                cluster_collection,
+                status,
+                found,
+            )
+        };
+        // the index is shared, the collections are not locked while clustering
+        let index = {
+            let collections = collections.read().map_err(|_| "RwLock Error")?;
+            collections
+                .get(&cluster_collection.collection_name)
+                .map(|collection| (collection.index.clone(), collection.index_config.metric()))
+        };
+        let (index, metric) = match index {
+            Some(index) => index,
+            None => {
+                return set_status(JobStatus::Failed("Unknown collection".into()), vec![]);
             }
-            drop(collection_write);
         };
-        println!("Finished");
+        set_status(JobStatus::Running, vec![])?;
+        println!(
+            "Clustering {} into {} clusters",
+            cluster_collection.collection_name, cluster_collection.k
+        );
+        let found = cluster_index(
+            &index,
+            metric,
+            cluster_collection.k,
+            cluster_collection.n_iterations,
+            cluster_collection.seed,
+        );
+        println!("Found {} clusters", found.len());
+        set_status(JobStatus::Done, found)
+    }
 
-        Ok(())
+    fn load_query(query: QuerySource) -> Result<Input, Box<dyn Error>> {
+        match query {
+            QuerySource::Source(source) => EmbeddingApp::load_source(&source),
+            QuerySource::Id(id) => Ok(Input::Id(id)),
+        }
     }
 
-    fn image_source_to_rgb_image(
-        image_source: &ImageSource,
-    ) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, Box<dyn Error>> {
+    fn load_source(image_source: &ImageSource) -> Result<Input, Box<dyn Error>> {
         println!("Add Image");
         let image_bytes = match &image_source {
             ImageSource::ImageBytes(image_bytes) => image_bytes.bytes.clone(),
             ImageSource::Url(url) => read_bytes_url(url.as_str())?.to_vec(),
+            ImageSource::Vector(vector) => {
+                return Ok(Input::Vector(vector.iter().map(|&x| x as f64).collect()))
+            }
         };
         println!("Converting bytes to RgbImage");
         let bytes = bytes::Bytes::from(image_bytes);
         let image = image_from_bytes(&bytes)?;
         println!("Converted");
-        Ok(image)
+        Ok(Input::Image(image))
+    }
+}
+
+/// Weighted sum of the embeddings of the examples divided by the total weight
+/// of the positive ones, negative examples have negative weights. With the
+/// cosine metric every embedding is normalized first so that all examples
+/// count alike.
+fn combine_examples(
+    metric: DistanceMetric,
+    examples: Vec<(Vec<f64>, f64)>,
+    positive_weight: f64,
+) -> Result<Vec<f64>, Box<dyn Error>> {
+    let dimension = examples.first().map_or(0, |(v, _)| v.len());
+    let mut combined = vec![0.0; dimension];
+    for (v, weight) in examples {
+        if v.len() != dimension {
+            return Err(format!(
+                "Examples have different dimensions: {} and {}",
+                dimension,
+                v.len()
+            )
+            .into());
+        }
+        let norm = match metric {
+            DistanceMetric::Cosine => v.iter().map(|x| x * x).sum::<f64>().sqrt(),
+            _ => 1.0,
+        };
+        if norm > 0.0 {
+            for (c, x) in combined.iter_mut().zip(&v) {
+                *c += weight * x / norm;
+            }
+        }
     }
+    combined.iter_mut().for_each(|c| *c /= positive_weight);
+    Ok(combined)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::image_transform::color::ColorSpace;
+    use crate::image_transform::hashing::HashAlgorithm;
+    use crate::index::db::IndexBackend;
+    use crate::index::wal::{UPLOADS_DIR, WAL_FILE};
     use reqwest::Url;
     use std::str::FromStr;
 
@@ -229,30 +1842,961 @@ mod tests {
         app.upsert_collection(&UpsertCollection {
             name: "images".to_string(),
             config: GenericModelConfig::ModelArchitecture(ModelArchitecture::MobileNetV2),
-        });
+            index_config: Default::default(),
+        })
+        .unwrap();
 
         app.add_image(AddImage{
             source: ImageSource::Url(Url::from_str("https://raw.githubusercontent.com/EliSchwartz/imagenet-sample-images/master/n01443537_goldfish.JPEG").unwrap()),
             collection_name: "images".into(),
-            id: "goldfish".into()
-        });
+            id: "goldfish".into(),
+            metadata: Default::default(),
+        }).unwrap();
 
         app.add_image(AddImage{
             source: ImageSource::Url(Url::from_str("https://raw.githubusercontent.com/EliSchwartz/imagenet-sample-images/master/n01491361_tiger_shark.JPEG").unwrap()),
             collection_name: "images".into(),
-            id: "shark".into()
-        });
+            id: "shark".into(),
+            metadata: Default::default(),
+        }).unwrap();
 
         app.add_image(AddImage{
             source: ImageSource::Url(Url::from_str("https://raw.githubusercontent.com/EliSchwartz/imagenet-sample-images/master/n01496331_electric_ray.JPEG").unwrap()),
             collection_name: "images".into(),
-            id: "ray".into()
-        });
+            id: "ray".into(),
+            metadata: Default::default(),
+        }).unwrap();
 
         app.add_image(AddImage{
             source: ImageSource::Url(Url::from_str("https://raw.githubusercontent.com/EliSchwartz/imagenet-sample-images/master/n01622779_great_grey_owl.JPEG").unwrap()),
             collection_name: "images".into(),
-            id: "owl".into()
-        });
+            id: "owl".into(),
+            metadata: Default::default(),
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_external_embedding() {
+        let app = EmbeddingApp::new(1);
+        app.upsert_collection(&UpsertCollection {
+            name: "vectors".to_string(),
+            config: GenericModelConfig::ExternalEmbedding(ExternalEmbedding { dimension: 2 }),
+            index_config: IndexConfig {
+                full_vectors: true,
+                ..Default::default()
+            },
+        })
+        .unwrap();
+        for i in 0..10 {
+            let add_image = AddImage {
+                source: ImageSource::Vector(vec![i as f32, 0.0]),
+                collection_name: "vectors".into(),
+                id: i.to_string(),
+                metadata: Default::default(),
+            };
+            app.add_image_to_collection(&add_image).unwrap();
+        }
+
+        let wrong_dimension = AddImage {
+            source: ImageSource::Vector(vec![1.0]),
+            collection_name: "vectors".into(),
+            id: "wrong".into(),
+            metadata: Default::default(),
+        };
+        assert!(app.add_image(wrong_dimension).is_err());
+        let image = AddImage {
+            source: ImageSource::ImageBytes(ImageBytes { bytes: vec![] }),
+            collection_name: "vectors".into(),
+            id: "image".into(),
+            metadata: Default::default(),
+        };
+        assert!(app.add_image(image).is_err());
+        assert!(app.job_queue.get_work().is_none());
+
+        let result = app
+            .search_image(SearchImage {
+                source: Some(ImageSource::Vector(vec![3.2, 0.0])),
+                positive: vec![],
+                negative: vec![],
+                collection_name: "vectors".into(),
+                n_results: 2,
+                ef: None,
+                filter: None,
+                diversify: None,
+                rerank: Some(Rerank { oversample: 2 }),
+            })
+            .unwrap();
+        let ids: Vec<_> = result.results.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, ["3", "4"]);
+
+        // re-ranking needs the full vectors
+        add_vectors(&app, "rounded", Default::default(), &[vec![0.0, 0.0]]);
+        assert!(app
+            .search_image(SearchImage {
+                source: Some(ImageSource::Vector(vec![3.2, 0.0])),
+                positive: vec![],
+                negative: vec![],
+                collection_name: "rounded".into(),
+                n_results: 2,
+                ef: None,
+                filter: None,
+                diversify: None,
+                rerank: Some(Rerank { oversample: 2 }),
+            })
+            .is_err());
+
+        let similar = app
+            .search_similar(SearchSimilar {
+                collection_name: "vectors".into(),
+                id: "3".into(),
+                n_results: 2,
+                ef: None,
+                filter: None,
+                diversify: None,
+                rerank: Some(Rerank { oversample: 2 }),
+            })
+            .unwrap()
+            .unwrap();
+        let ids: Vec<_> = similar.results.iter().map(|r| r.id.as_str()).collect();
+        assert!(ids.iter().all(|&id| id == "2" || id == "4"));
+        assert_eq!(ids.len(), 2);
+
+        let batch = app
+            .batch_search(BatchSearch {
+                collection_name: "vectors".into(),
+                queries: vec![
+                    QuerySource::Source(ImageSource::Vector(vec![7.1, 0.0])),
+                    QuerySource::Id("unknown".into()),
+                    QuerySource::Id("0".into()),
+                    QuerySource::Source(ImageSource::Vector(vec![1.0, 0.0, 0.0])),
+                ],
+                n_results: 1,
+                ef: None,
+                filter: None,
+                rerank: None,
+            })
+            .unwrap()
+            .unwrap();
+        let ids: Vec<_> = batch
+            .results
+            .iter()
+            .map(|item| item.results.first().map(|r| r.id.as_str()))
+            .collect();
+        assert_eq!(ids, [Some("7"), None, Some("1"), None]);
+        assert!(batch.results[0].error.is_none());
+        assert!(batch.results[1].error.is_some());
+        assert!(batch.results[3].error.is_some());
+
+        let range = app
+            .range_search(RangeSearch {
+                collection_name: "vectors".into(),
+                query: QuerySource::Id("5".into()),
+                radius: 2.0,
+                max_results: None,
+                ef: None,
+                filter: None,
+            })
+            .unwrap()
+            .unwrap();
+        let mut ids: Vec<_> = range.results.iter().map(|r| r.id.as_str()).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, ["3", "4", "6", "7"]);
+
+        app.add_image_to_collection(&AddImage {
+            source: ImageSource::Vector(vec![3.01, 0.0]),
+            collection_name: "vectors".into(),
+            id: "3 copy".into(),
+            metadata: Default::default(),
+        })
+        .unwrap();
+        let find_duplicates = FindDuplicates {
+            collection_name: "vectors".into(),
+            threshold: 0.1,
+        };
+        assert!(app.find_duplicates(find_duplicates.clone()).unwrap());
+        let get_duplicates = GetDuplicates {
+            collection_name: "vectors".into(),
+        };
+        let report = app.duplicate_report(&get_duplicates).unwrap().unwrap();
+        assert_eq!(report.status, JobStatus::Queued);
+        EmbeddingApp::find_duplicates_in_collection(
+            app.collections.clone(),
+            app.duplicates.clone(),
+            &find_duplicates,
+        )
+        .unwrap();
+        let report = app.duplicate_report(&get_duplicates).unwrap().unwrap();
+        assert_eq!(report.status, JobStatus::Done);
+        assert_eq!(report.results.len(), 1);
+        assert_eq!(report.results[0].ids, ["3", "3 copy"]);
+        assert_eq!(report.to_jsonl().unwrap().lines().count(), 1);
+    }
+
+    fn add_vectors(
+        app: &EmbeddingApp,
+        collection_name: &str,
+        index_config: IndexConfig,
+        vectors: &[Vec<f32>],
+    ) {
+        app.upsert_collection(&UpsertCollection {
+            name: collection_name.to_string(),
+            config: GenericModelConfig::ExternalEmbedding(ExternalEmbedding {
+                dimension: vectors[0].len(),
+            }),
+            index_config,
+        })
+        .unwrap();
+        for (i, v) in vectors.iter().enumerate() {
+            let add_image = AddImage {
+                source: ImageSource::Vector(v.clone()),
+                collection_name: collection_name.into(),
+                id: i.to_string(),
+                metadata: Default::default(),
+            };
+            app.add_image_to_collection(&add_image).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_federated_search() {
+        let app = EmbeddingApp::new(1);
+        add_vectors(
+            &app,
+            "us",
+            Default::default(),
+            &[vec![0.0, 0.0], vec![2.0, 0.0]],
+        );
+        add_vectors(
+            &app,
+            "eu",
+            Default::default(),
+            &[vec![1.0, 0.0], vec![3.0, 0.0]],
+        );
+        add_vectors(&app, "3d", Default::default(), &[vec![1.0, 0.0, 0.0]]);
+        let cosine = IndexConfig {
+            metric: Some(DistanceMetric::Cosine),
+            ..Default::default()
+        };
+        add_vectors(&app, "cosine", cosine, &[vec![1.0, 0.0]]);
+
+        let search = |collection_names: &[&str]| {
+            app.federated_search(FederatedSearch {
+                collection_names: collection_names.iter().map(|n| n.to_string()).collect(),
+                source: ImageSource::Vector(vec![0.9, 0.0]),
+                n_results: 3,
+                ef: None,
+                filter: None,
+            })
+        };
+        let results = search(&["us", "eu"]).unwrap().results;
+        let hits: Vec<_> = results
+            .iter()
+            .map(|r| (r.collection_name.as_str(), r.id.as_str()))
+            .collect();
+        assert_eq!(hits, [("eu", "0"), ("us", "0"), ("us", "1")]);
+
+        assert!(search(&["us", "3d"]).is_err());
+        assert!(search(&["us", "cosine"]).is_err());
+        assert!(search(&["us", "unknown"]).is_err());
+    }
+
+    #[test]
+    fn test_diversified_search() {
+        let app = EmbeddingApp::new(1);
+        // the first three are the same image
+        let vectors = [
+            vec![1.0, 0.0],
+            vec![1.0, 0.01],
+            vec![1.0, -0.01],
+            vec![0.0, 1.5],
+            vec![-1.5, 0.0],
+        ];
+        add_vectors(&app, "shoes", Default::default(), &vectors);
+        let search = |diversify: Option<Mmr>| {
+            let results = app
+                .search_image(SearchImage {
+                    source: Some(ImageSource::Vector(vec![0.0, 0.0])),
+                    positive: vec![],
+                    negative: vec![],
+                    collection_name: "shoes".into(),
+                    n_results: 2,
+                    ef: None,
+                    filter: None,
+                    diversify,
+                    rerank: None,
+                })
+                .unwrap()
+                .results;
+            results.into_iter().map(|r| r.id).collect::<Vec<_>>()
+        };
+        assert!(search(None)
+            .iter()
+            .all(|id| ["0", "1", "2"].contains(&id.as_str())));
+        let diverse = search(Some(Mmr {
+            lambda: 0.5,
+            n_candidates: None,
+        }));
+        assert_eq!(diverse.len(), 2);
+        assert!(["3", "4"].contains(&diverse[1].as_str()));
+    }
+
+    #[test]
+    fn test_multi_example_search() {
+        let app = EmbeddingApp::new(1);
+        let vectors = [
+            vec![0.0, 0.0],
+            vec![2.0, 0.0],
+            vec![0.0, 2.0],
+            vec![2.0, 2.0],
+            vec![1.0, 1.0],
+        ];
+        add_vectors(&app, "dresses", Default::default(), &vectors);
+        let search = |source: Option<Vec<f32>>,
+                      positive: Vec<WeightedQuery>,
+                      negative: Vec<WeightedQuery>| {
+            app.search_image(SearchImage {
+                source: source.map(ImageSource::Vector),
+                positive,
+                negative,
+                collection_name: "dresses".into(),
+                n_results: 1,
+                ef: None,
+                filter: None,
+                diversify: None,
+                rerank: None,
+            })
+            .map(|result| result.results[0].id.clone())
+        };
+        let id = |id: &str, weight: f64| WeightedQuery {
+            query: QuerySource::Id(id.into()),
+            weight,
+        };
+
+        // the mean of two examples, which are not results themselves
+        assert_eq!(
+            search(None, vec![id("1", 1.0), id("2", 1.0)], vec![]).unwrap(),
+            "4"
+        );
+        let positive = vec![WeightedQuery {
+            query: QuerySource::Source(ImageSource::Vector(vec![1.0, 1.0])),
+            weight: 2.0,
+        }];
+        assert_eq!(search(None, positive, vec![id("3", 1.0)]).unwrap(), "0");
+        assert_eq!(search(Some(vec![2.0, 2.0]), vec![], vec![]).unwrap(), "3");
+
+        assert!(search(None, vec![], vec![id("3", 1.0)]).is_err());
+        assert!(search(None, vec![id("unknown", 1.0)], vec![]).is_err());
+    }
+
+    #[test]
+    fn test_cluster_collection() {
+        let app = EmbeddingApp::new(1);
+        let vectors = [
+            vec![0.0, 0.0],
+            vec![0.1, 0.0],
+            vec![0.0, 0.2],
+            vec![10.0, 10.0],
+            vec![10.1, 10.0],
+        ];
+        add_vectors(&app, "catalogue", Default::default(), &vectors);
+
+        let cluster_collection = ClusterCollection {
+            collection_name: "catalogue".into(),
+            k: 2,
+            n_iterations: 20,
+            seed: 0,
+        };
+        assert!(app
+            .cluster_collection(ClusterCollection {
+                k: 0,
+                ..cluster_collection.clone()
+            })
+            .is_err());
+        assert!(!app
+            .cluster_collection(ClusterCollection {
+                collection_name: "unknown".into(),
+                ..cluster_collection.clone()
+            })
+            .unwrap());
+        assert!(app.cluster_collection(cluster_collection.clone()).unwrap());
+        let get_clusters = GetClusters {
+            collection_name: "catalogue".into(),
+            n_central: 1,
+        };
+        let result = app.clusters(&get_clusters).unwrap().unwrap();
+        assert_eq!(result.status, JobStatus::Queued);
+
+        EmbeddingApp::cluster_images_in_collection(
+            app.collections.clone(),
+            app.clusters.clone(),
+            &cluster_collection,
+        )
+        .unwrap();
+        let result = app.clusters(&get_clusters).unwrap().unwrap();
+        assert_eq!(result.status, JobStatus::Done);
+        let sizes: Vec<_> = result.clusters.iter().map(|c| c.size).collect();
+        assert_eq!(sizes, [3, 2]);
+        assert_eq!(result.clusters[0].central.len(), 1);
+        assert_eq!(result.clusters[0].central[0].id, "0");
+
+        let members = app
+            .cluster_members(&GetClusterMembers {
+                collection_name: "catalogue".into(),
+                cluster: 1,
+            })
+            .unwrap()
+            .unwrap();
+        let mut ids: Vec<_> = members.iter().map(|m| m.id.as_str()).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, ["3", "4"]);
+        assert!(app
+            .cluster_members(&GetClusterMembers {
+                collection_name: "catalogue".into(),
+                cluster: 2,
+            })
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_pca_collection() {
+        let app = EmbeddingApp::new(1);
+        let pca = PcaConfig {
+            dimension: 2,
+            whiten: false,
+            n_training: 20,
+        };
+        // spread along the first two axes, the third is noise
+        let vectors: Vec<Vec<f32>> = (0..30)
+            .map(|i| vec![(i % 6) as f32, (i / 6) as f32 * 2.0, (i % 2) as f32 * 0.01])
+            .collect();
+        add_vectors(
+            &app,
+            "projected",
+            IndexConfig {
+                backend: IndexBackend::Flat,
+                pca: Some(pca),
+                ..Default::default()
+            },
+            &vectors[..19],
+        );
+        let dimension = |name: &str| {
+            let collections = app.collections.read().unwrap();
+            let collection = &collections[name];
+            collection.index.vector("0").unwrap().len()
+        };
+        assert_eq!(dimension("projected"), 3);
+        for (i, v) in vectors.iter().enumerate().skip(19) {
+            let add_image = AddImage {
+                source: ImageSource::Vector(v.clone()),
+                collection_name: "projected".into(),
+                id: i.to_string(),
+                metadata: Default::default(),
+            };
+            app.add_image_to_collection(&add_image).unwrap();
+        }
+        assert_eq!(dimension("projected"), 2);
+        assert_eq!(app.collections.read().unwrap()["projected"].index.len(), 30);
+
+        // queries are projected too, distances are kept along the components
+        let result = app
+            .search_image(SearchImage {
+                source: Some(ImageSource::Vector(vec![2.0, 4.0, 0.5])),
+                positive: vec![],
+                negative: vec![],
+                collection_name: "projected".into(),
+                n_results: 1,
+                ef: None,
+                filter: None,
+                diversify: None,
+                rerank: None,
+            })
+            .unwrap();
+        assert_eq!(result.results[0].id, "14");
+        assert!(result.results[0].distance < 0.1);
+
+        let fit_pca = |collection_name: &str, n: usize| {
+            app.fit_pca(FitPca {
+                collection_name: collection_name.into(),
+                sample: vectors[..n]
+                    .iter()
+                    .map(|v| ImageSource::Vector(v.clone()))
+                    .collect(),
+            })
+        };
+        assert!(fit_pca("projected", 10).is_err());
+        assert!(!fit_pca("unknown", 10).unwrap());
+        add_vectors(&app, "raw", Default::default(), &vectors);
+        assert!(fit_pca("raw", 10).is_err());
+
+        // an explicit sample fits the projection before enough images are indexed
+        add_vectors(
+            &app,
+            "sampled",
+            IndexConfig {
+                pca: Some(PcaConfig {
+                    n_training: 1000,
+                    ..pca
+                }),
+                ..Default::default()
+            },
+            &vectors[..5],
+        );
+        assert!(fit_pca("sampled", 1).is_err());
+        assert!(fit_pca("sampled", 10).unwrap());
+        let job = match app.job_queue.get_work() {
+            Some(Job::FitPca(job)) => job,
+            _ => panic!("Expected a FitPca job"),
+        };
+        EmbeddingApp::fit_pca_in_collection(app.collections.clone(), &job).unwrap();
+        assert_eq!(dimension("sampled"), 2);
+        assert!(app
+            .federated_search(FederatedSearch {
+                collection_names: vec!["projected".into(), "raw".into()],
+                source: ImageSource::Vector(vec![0.0, 0.0, 0.0]),
+                n_results: 1,
+                ef: None,
+                filter: None,
+            })
+            .is_err());
+
+        // changes made while the PCA is fitted are replayed on the new index
+        add_vectors(
+            &app,
+            "concurrent",
+            IndexConfig {
+                backend: IndexBackend::Flat,
+                pca: Some(pca),
+                ..Default::default()
+            },
+            &vectors[..10],
+        );
+        let fit = app.collections.read().unwrap()["concurrent"]
+            .begin_fit_pca()
+            .unwrap()
+            .unwrap();
+        assert!(app.collections.read().unwrap()["concurrent"]
+            .begin_fit_pca()
+            .unwrap()
+            .is_none());
+        let add_image = AddImage {
+            source: ImageSource::Vector(vectors[29].clone()),
+            collection_name: "concurrent".into(),
+            id: "29".into(),
+            metadata: Default::default(),
+        };
+        app.add_image_to_collection(&add_image).unwrap();
+        assert!(app.collections.read().unwrap()["concurrent"]
+            .remove_image("1")
+            .unwrap());
+        let fitted = fit.fit(None);
+        app.collections
+            .write()
+            .unwrap()
+            .get_mut("concurrent")
+            .unwrap()
+            .finish_fit_pca(fitted)
+            .unwrap();
+        assert_eq!(dimension("concurrent"), 2);
+        {
+            let collections = app.collections.read().unwrap();
+            let index = &collections["concurrent"].index;
+            assert_eq!(index.len(), 10);
+            assert_eq!(index.vector("29").unwrap().len(), 2);
+            assert!(index.vector("1").is_none());
+        }
+
+        // a PCA cannot add dimensions
+        for dimension in &[0, 4] {
+            assert!(app
+                .upsert_collection(&UpsertCollection {
+                    name: "invalid".into(),
+                    config: GenericModelConfig::ExternalEmbedding(ExternalEmbedding {
+                        dimension: 3
+                    }),
+                    index_config: IndexConfig {
+                        pca: Some(PcaConfig {
+                            dimension: *dimension,
+                            ..pca
+                        }),
+                        ..Default::default()
+                    },
+                })
+                .is_err());
+        }
+        assert!(!app.collections.read().unwrap().contains_key("invalid"));
+
+        // the fitted projection is part of the snapshot
+        let data_dir = std::env::temp_dir().join("visual-search-pca-snapshot");
+        let _ = std::fs::remove_dir_all(&data_dir);
+        app.save_snapshot(&data_dir).unwrap();
+        let mut recovered = EmbeddingApp::new(1);
+        recovered.recover(&data_dir).unwrap();
+        let collections = recovered.collections.read().unwrap();
+        let original = app.collections.read().unwrap();
+        let projected = &collections["projected"];
+        assert_eq!(
+            projected.pca.as_ref().unwrap().components,
+            original["projected"].pca.as_ref().unwrap().components
+        );
+        assert_eq!(
+            projected
+                .extract_features(Input::Vector(vec![1.0, 2.0, 3.0]))
+                .unwrap(),
+            original["projected"]
+                .extract_features(Input::Vector(vec![1.0, 2.0, 3.0]))
+                .unwrap()
+        );
+        assert!(collections["raw"].pca.is_none());
+    }
+
+    #[test]
+    fn test_recover() {
+        let data_dir = std::env::temp_dir().join("visual-search-recover");
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let mut app = EmbeddingApp::new(1);
+        app.recover(&data_dir).unwrap();
+        app.upsert_collection(&UpsertCollection {
+            name: "vectors".into(),
+            config: GenericModelConfig::ExternalEmbedding(ExternalEmbedding { dimension: 2 }),
+            index_config: Default::default(),
+        })
+        .unwrap();
+        app.upsert_collection(&UpsertCollection {
+            name: "colors".into(),
+            config: GenericModelConfig::ColorHistogram(ColorHistogram {
+                color_space: ColorSpace::Hsv,
+                bins: [8, 4, 4],
+                pyramid_levels: 0,
+                moments: false,
+            }),
+            index_config: Default::default(),
+        })
+        .unwrap();
+        let add_to = |collection_name: &str, id: &str, source: ImageSource| {
+            app.add_image(AddImage {
+                source,
+                collection_name: collection_name.into(),
+                id: id.into(),
+                metadata: Default::default(),
+            })
+            .unwrap();
+        };
+        let add_image = |id: &str, source: ImageSource| add_to("vectors", id, source);
+
+        add_image("snapshot", ImageSource::Vector(vec![0.0, 0.0]));
+        assert!(app.run_next_job());
+        // queued while the snapshot is taken, so it is only in the new log
+        add_image("queued", ImageSource::Vector(vec![1.0, 0.0]));
+        app.save_snapshot(&data_dir).unwrap();
+        assert!(app.run_next_job());
+        // the download fails, recovering does not try again
+        add_to(
+            "colors",
+            "unreachable",
+            ImageSource::Url("http://127.0.0.1:1/missing.jpg".parse().unwrap()),
+        );
+        assert!(app.run_next_job());
+        // a crash while writing a snapshot leaves the previous log behind
+        app.wal
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .rotate(&app.pending_events().unwrap())
+            .unwrap();
+        add_image("after rotation", ImageSource::Vector(vec![3.0, 0.0]));
+        assert!(app.run_next_job());
+        add_image("removed", ImageSource::Vector(vec![4.0, 0.0]));
+        assert!(app
+            .remove_image(RemoveImage {
+                collection_name: "vectors".into(),
+                id: "removed".into(),
+            })
+            .unwrap());
+        let cat = std::fs::read("images/cat.jpeg").unwrap();
+        let upload = || ImageSource::ImageBytes(ImageBytes { bytes: cat.clone() });
+        // its upload is deleted once it is indexed
+        add_to("colors", "indexed upload", upload());
+        assert!(app.run_next_job());
+        add_to("colors", "queued upload", upload());
+        // the bytes are not in the log
+        let log_len = std::fs::metadata(data_dir.join(WAL_FILE)).unwrap().len();
+        assert!(log_len < cat.len() as u64);
+        assert_eq!(
+            std::fs::read_dir(data_dir.join(UPLOADS_DIR))
+                .unwrap()
+                .count(),
+            1
+        );
+        add_image("not run", ImageSource::Vector(vec![2.0, 0.0]));
+        drop(app);
+
+        let mut recovered = EmbeddingApp::new(1);
+        recovered.recover(&data_dir).unwrap();
+        {
+            let collections = recovered.collections.read().unwrap();
+            let mut ids = collections["vectors"].index.ids();
+            ids.sort_unstable();
+            assert_eq!(ids, ["after rotation", "queued", "snapshot"]);
+            assert_eq!(collections["colors"].index.ids(), ["indexed upload"]);
+        }
+        let queued: Vec<Job> = recovered
+            .job_queue
+            .inner
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        match &queued[..] {
+            [Job::AddImage(uploaded), Job::AddImage(not_run)] => {
+                assert_eq!(uploaded.id, "queued upload");
+                assert_eq!(uploaded.source, upload());
+                assert_eq!(not_run.id, "not run");
+            }
+            _ => panic!("Expected two AddImage jobs, got {} jobs", queued.len()),
+        }
+        // a snapshot taken while the upload is still queued keeps it
+        recovered.save_snapshot(&data_dir).unwrap();
+        assert_eq!(
+            std::fs::read_dir(data_dir.join(UPLOADS_DIR))
+                .unwrap()
+                .count(),
+            1
+        );
+        assert!(recovered.run_next_job());
+        assert!(recovered.run_next_job());
+        recovered.save_snapshot(&data_dir).unwrap();
+        assert!(read_events(&data_dir).unwrap().is_empty());
+        assert_eq!(
+            std::fs::read_dir(data_dir.join(UPLOADS_DIR))
+                .unwrap()
+                .count(),
+            0
+        );
+        let collections = recovered.collections.read().unwrap();
+        assert_eq!(collections["vectors"].index.len(), 4);
+        assert_eq!(collections["colors"].index.len(), 2);
+    }
+
+    #[test]
+    fn test_remove_image_being_added() {
+        let app = EmbeddingApp::new(1);
+        app.upsert_collection(&UpsertCollection {
+            name: "vectors".into(),
+            config: GenericModelConfig::ExternalEmbedding(ExternalEmbedding { dimension: 2 }),
+            index_config: Default::default(),
+        })
+        .unwrap();
+        let add_image = |id: &str| AddImage {
+            source: ImageSource::Vector(vec![0.0, 1.0]),
+            collection_name: "vectors".into(),
+            id: id.into(),
+            metadata: Default::default(),
+        };
+        let remove_image = |id: &str| {
+            app.remove_image(RemoveImage {
+                collection_name: "vectors".into(),
+                id: id.into(),
+            })
+            .unwrap()
+        };
+
+        // taken by a worker which has not finished yet
+        app.add_image(add_image("running")).unwrap();
+        let job = app.job_queue.get_work().unwrap();
+        app.in_progress.lock().unwrap().push(job);
+        app.add_image(add_image("queued")).unwrap();
+
+        assert!(remove_image("running"));
+        assert!(remove_image("queued"));
+        assert!(!remove_image("unknown"));
+        app.add_image_to_collection(&add_image("running")).unwrap();
+        assert!(!app.run_next_job());
+        assert!(app.collections.read().unwrap()["vectors"].index.is_empty());
+    }
+
+    #[test]
+    fn test_perceptual_hash_collection() {
+        let app = EmbeddingApp::new(1);
+        let upsert = |hash_size: u32, index_config: IndexConfig| {
+            app.upsert_collection(&UpsertCollection {
+                name: "uploads".into(),
+                config: GenericModelConfig::PerceptualHash(PerceptualHash {
+                    algorithm: HashAlgorithm::PHash,
+                    hash_size,
+                }),
+                index_config,
+            })
+        };
+        let hamming = IndexConfig {
+            metric: Some(DistanceMetric::Hamming),
+            ..Default::default()
+        };
+        // conflicting configs are rejected rather than changed
+        let euclidean = IndexConfig {
+            metric: Some(DistanceMetric::Euclidean),
+            ..Default::default()
+        };
+        assert!(upsert(8, euclidean).is_err());
+        assert!(upsert(
+            8,
+            IndexConfig {
+                pca: Some(PcaConfig {
+                    dimension: 16,
+                    whiten: false,
+                    n_training: 100,
+                }),
+                ..hamming.clone()
+            }
+        )
+        .is_err());
+        assert!(upsert(0, hamming.clone()).is_err());
+        assert!(upsert(64, hamming.clone()).is_err());
+        assert!(app.collections.read().unwrap().is_empty());
+        // the metric of hash collections is Hamming by default
+        upsert(8, Default::default()).unwrap();
+        assert_eq!(
+            app.collections.read().unwrap()["uploads"]
+                .index_config
+                .metric(),
+            DistanceMetric::Hamming
+        );
+
+        let cat = std::fs::read("images/cat.jpeg").unwrap();
+        // the same photo uploaded again smaller and as png
+        let image = image::load_from_memory(&cat).unwrap();
+        let mut reupload = Vec::new();
+        image
+            .thumbnail(image.width() / 2, image.height() / 2)
+            .write_to(
+                &mut std::io::Cursor::new(&mut reupload),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+        let other = std::fs::read("images/imagenet-sample-images/0.JPEG").unwrap();
+        for (id, bytes) in &[("cat", &cat), ("reupload", &reupload), ("other", &other)] {
+            let add_image = AddImage {
+                source: ImageSource::ImageBytes(ImageBytes {
+                    bytes: bytes.to_vec(),
+                }),
+                collection_name: "uploads".into(),
+                id: id.to_string(),
+                metadata: Default::default(),
+            };
+            app.add_image_to_collection(&add_image).unwrap();
+        }
+
+        let result = app
+            .search_image(SearchImage {
+                source: Some(ImageSource::ImageBytes(ImageBytes { bytes: cat })),
+                positive: vec![],
+                negative: vec![],
+                collection_name: "uploads".into(),
+                n_results: 3,
+                ef: None,
+                filter: None,
+                diversify: None,
+                rerank: None,
+            })
+            .unwrap();
+        let ids: Vec<_> = result.results.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, ["cat", "reupload", "other"]);
+        assert_eq!(result.results[0].distance, 0.0);
+        assert!(result.results[1].distance <= 4.0);
+        assert!(result.results[2].distance > 10.0);
+
+        // hashes computed elsewhere can be added as vectors of bits
+        let collections = app.collections.read().unwrap();
+        let uploads = &collections["uploads"];
+        assert!(uploads
+            .check_source(&ImageSource::Vector(vec![0.0; 64]))
+            .is_ok());
+        assert!(uploads
+            .check_source(&ImageSource::Vector(vec![0.0; 32]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_color_histogram_collection() {
+        let app = EmbeddingApp::new(1);
+        let histogram = ColorHistogram {
+            color_space: ColorSpace::Hsv,
+            bins: [8, 4, 4],
+            pyramid_levels: 1,
+            moments: true,
+        };
+        app.upsert_collection(&UpsertCollection {
+            name: "swatches".into(),
+            config: GenericModelConfig::ColorHistogram(histogram.clone()),
+            index_config: Default::default(),
+        })
+        .unwrap();
+        assert!(app
+            .upsert_collection(&UpsertCollection {
+                name: "too large".into(),
+                config: GenericModelConfig::ColorHistogram(ColorHistogram {
+                    pyramid_levels: 10,
+                    ..histogram.clone()
+                }),
+                index_config: Default::default(),
+            })
+            .is_err());
+        assert!(!app.collections.read().unwrap().contains_key("too large"));
+
+        let png = |color: [u8; 3]| {
+            let image = image::RgbImage::from_pixel(32, 32, image::Rgb(color));
+            let mut bytes = Vec::new();
+            image::DynamicImage::ImageRgb8(image)
+                .write_to(
+                    &mut std::io::Cursor::new(&mut bytes),
+                    image::ImageOutputFormat::Png,
+                )
+                .unwrap();
+            bytes
+        };
+        let colors = [
+            ("red", [220, 20, 20]),
+            ("dark red", [150, 10, 10]),
+            ("blue", [20, 20, 220]),
+        ];
+        for (id, color) in &colors {
+            let add_image = AddImage {
+                source: ImageSource::ImageBytes(ImageBytes { bytes: png(*color) }),
+                collection_name: "swatches".into(),
+                id: id.to_string(),
+                metadata: Default::default(),
+            };
+            app.add_image_to_collection(&add_image).unwrap();
+        }
+
+        let result = app
+            .search_image(SearchImage {
+                source: Some(ImageSource::ImageBytes(ImageBytes {
+                    bytes: png([220, 20, 20]),
+                })),
+                positive: vec![],
+                negative: vec![],
+                collection_name: "swatches".into(),
+                n_results: 3,
+                ef: None,
+                filter: None,
+                diversify: None,
+                rerank: None,
+            })
+            .unwrap();
+        let ids: Vec<_> = result.results.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, ["red", "dark red", "blue"]);
+        assert!(result.results[0].distance < 1e-6);
+
+        let collections = app.collections.read().unwrap();
+        let swatches = &collections["swatches"];
+        assert!(swatches
+            .check_source(&ImageSource::Vector(vec![0.0; histogram.dimension()]))
+            .is_ok());
+        assert!(swatches
+            .check_source(&ImageSource::Vector(vec![0.0; 3]))
+            .is_err());
     }
 }
@@ -1,2 +1,4 @@
 pub mod app;
+pub mod parallel;
+pub mod persistence;
 pub mod work_queue;
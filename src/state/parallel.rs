@@ -0,0 +1,45 @@
+use std::thread;
+
+/// Applies `f` to every item on up to one thread per core and returns the
+/// results in the order of `items`.
+pub fn parallel_map<T, R, F>(items: Vec<T>, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let n_threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(items.len())
+        .max(1);
+    let chunk_size = items.len().div_ceil(n_threads).max(1);
+    let mut items = items.into_iter();
+    let chunks: Vec<Vec<T>> = (0..n_threads)
+        .map(|_| items.by_ref().take(chunk_size).collect())
+        .collect();
+    let f = &f;
+    thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| scope.spawn(move || chunk.into_iter().map(f).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parallel_map() {
+        let items: Vec<usize> = (0..1000).collect();
+        let doubled = parallel_map(items, |i| i * 2);
+        assert_eq!(doubled, (0..1000).map(|i| i * 2).collect::<Vec<_>>());
+        assert!(parallel_map(Vec::<usize>::new(), |i| i).is_empty());
+    }
+}
@@ -0,0 +1,77 @@
+use crate::index::db::{CollectionIndex, IndexConfig};
+use crate::state::app::{Collection, CollectionName, GenericModelConfig, ImageId};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+pub const SNAPSHOT_FILE: &str = "snapshot.bin";
+
+/// Writes every collection (model config, index, metadata and fitted PCA) to
+/// `data_dir`.
+///
+/// The snapshot is first written to a temporary file and then renamed over the
+/// previous one so a crash in the middle of writing never leaves a broken snapshot.
+pub fn write_snapshot(
+    data_dir: &Path,
+    collections: &HashMap<CollectionName, Collection>,
+) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(data_dir)?;
+    let path = data_dir.join(SNAPSHOT_FILE);
+    let tmp_path = data_dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    bincode::serialize_into(&mut writer, &(collections.len() as u64))?;
+    for collection in collections.values() {
+        bincode::serialize_into(
+            &mut writer,
+            &(
+                &collection.name,
+                &collection.model_config,
+                &collection.index_config,
+            ),
+        )?;
+        collection.index.save(&mut writer)?;
+        // bincode cannot read arbitrary JSON so metadata is stored as JSON strings
+        let metadata = collection.metadata.read().map_err(|_| "RwLock Error")?;
+        let metadata: Vec<(&ImageId, String)> = metadata
+            .iter()
+            .map(|(id, m)| Ok((id, serde_json::to_string(m)?)))
+            .collect::<Result<_, serde_json::Error>>()?;
+        bincode::serialize_into(&mut writer, &metadata)?;
+        bincode::serialize_into(&mut writer, &collection.pca)?;
+    }
+    let file = writer.into_inner()?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Reads collections written by `write_snapshot`. Returns no collections if
+/// there is no snapshot in `data_dir` yet.
+pub fn read_snapshot(data_dir: &Path) -> Result<Vec<Collection>, Box<dyn Error>> {
+    let path = data_dir.join(SNAPSHOT_FILE);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut reader = BufReader::new(File::open(&path)?);
+    let n_collections: u64 = bincode::deserialize_from(&mut reader)?;
+    let mut collections = Vec::new();
+    for _ in 0..n_collections {
+        let (name, model_config, index_config): (CollectionName, GenericModelConfig, IndexConfig) =
+            bincode::deserialize_from(&mut reader)?;
+        let index = CollectionIndex::load(&index_config, &mut reader)?;
+        let mut collection = Collection::with_index(&name, &model_config, &index_config, index);
+        let metadata: Vec<(ImageId, String)> = bincode::deserialize_from(&mut reader)?;
+        *collection.metadata.write().map_err(|_| "RwLock Error")? = metadata
+            .into_iter()
+            .map(|(id, m)| Ok((id, serde_json::from_str(&m)?)))
+            .collect::<Result<_, serde_json::Error>>()?;
+        collection.pca = bincode::deserialize_from(&mut reader)?;
+        collections.push(collection);
+    }
+    Ok(collections)
+}
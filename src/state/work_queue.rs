@@ -50,3 +50,9 @@ impl<T: Send + PartialEq + Clone> WorkQueue<T> {
         q
     }
 }
+
+impl<T: Send + PartialEq + Clone> Default for WorkQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,21 +1,19 @@
-mod image_transform;
-mod index;
-mod state;
-
-use crate::image_transform::architectures::load_model_config;
-use crate::image_transform::functions::read_rgb_image;
-use crate::image_transform::models::{LoadedModel, ModelArchitecture};
-use crate::index::events::{ImageSource, RemoveCollection, UpsertCollection};
 use clap::App as ClapApp;
 use serde::{Deserialize, Serialize};
-use tract_onnx::prelude::*;
+use visual_search::index::events::{RemoveCollection, UpsertCollection};
 
-use crate::index::events::{AddImage, RemoveImage, SearchImage};
-use crate::state::app::EmbeddingApp;
 use actix_web::dev::ServiceRequest;
 use actix_web::web::Data;
 use actix_web::{error, get, post, web, App, Error, HttpRequest, HttpResponse, HttpServer, Result};
 use std::fs::read_to_string;
+use std::path::PathBuf;
+use std::time::Duration;
+use visual_search::index::events::{
+    AddImage, BatchSearch, ClusterCollection, FederatedSearch, FindDuplicates, FitPca,
+    GetClusterMembers, GetClusters, GetDuplicates, RangeSearch, RemoveImage, SearchImage,
+    SearchSimilar,
+};
+use visual_search::state::app::{DuplicateReport, EmbeddingApp};
 
 use actix_web_httpauth::extractors::bearer::{BearerAuth, Config};
 use actix_web_httpauth::extractors::AuthenticationError;
@@ -27,11 +25,18 @@ pub struct ServerConfig {
     pub port: u16,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PersistenceConfig {
+    pub data_dir: String,
+    pub snapshot_interval_secs: u64,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct AppConfig {
     pub n_workers: usize,
     pub server_config: ServerConfig,
     pub token: String,
+    pub persistence_config: Option<PersistenceConfig>,
 }
 
 #[get("/")]
@@ -40,28 +45,214 @@ async fn home(state: web::Data<EmbeddingApp>) -> String {
 }
 
 #[post("/add_image")]
-async fn add_image(state: web::Data<EmbeddingApp>, add_image: web::Json<AddImage>) -> String {
+async fn add_image(
+    state: web::Data<EmbeddingApp>,
+    add_image: web::Json<AddImage>,
+) -> Result<String> {
     println!("Add image");
-    state.add_image(add_image.into_inner()).unwrap();
-    "ok".into()
+    state
+        .add_image(add_image.into_inner())
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    Ok("ok".into())
 }
 
 #[post("/remove_image")]
 async fn remove_image(
     state: web::Data<EmbeddingApp>,
     remove_image: web::Json<RemoveImage>,
-) -> String {
-    state.remove_image(remove_image.into_inner()).unwrap();
-    "ok".into()
+) -> Result<String> {
+    let remove_image = remove_image.into_inner();
+    let id = remove_image.id.clone();
+    let removed = state
+        .remove_image(remove_image)
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?;
+    if !removed {
+        return Err(error::ErrorNotFound(format!("Unknown image id {}", id)));
+    }
+    Ok("ok".into())
 }
 
 #[post("/search_image")]
 async fn search_image(
     state: web::Data<EmbeddingApp>,
     search_image: web::Json<SearchImage>,
-) -> String {
-    let search_results = state.search_image(search_image.into_inner()).unwrap();
-    serde_json::to_string(&search_results).unwrap()
+) -> Result<String> {
+    let search_results = state
+        .search_image(search_image.into_inner())
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    Ok(serde_json::to_string(&search_results)?)
+}
+
+#[post("/search_similar")]
+async fn search_similar(
+    state: web::Data<EmbeddingApp>,
+    search_similar: web::Json<SearchSimilar>,
+) -> Result<String> {
+    let search_similar = search_similar.into_inner();
+    let id = search_similar.id.clone();
+    let search_results = state
+        .search_similar(search_similar)
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?
+        .ok_or_else(|| error::ErrorNotFound(format!("Unknown image id {}", id)))?;
+    Ok(serde_json::to_string(&search_results)?)
+}
+
+#[post("/range_search")]
+async fn range_search(
+    state: web::Data<EmbeddingApp>,
+    range_search: web::Json<RangeSearch>,
+) -> Result<String> {
+    let search_results = state
+        .range_search(range_search.into_inner())
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?
+        .ok_or_else(|| error::ErrorNotFound("Unknown collection or image id"))?;
+    Ok(serde_json::to_string(&search_results)?)
+}
+
+#[post("/batch_search")]
+async fn batch_search(
+    state: web::Data<EmbeddingApp>,
+    batch_search: web::Json<BatchSearch>,
+) -> Result<String> {
+    let batch_search = batch_search.into_inner();
+    let collection_name = batch_search.collection_name.clone();
+    let search_results = state
+        .batch_search(batch_search)
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?
+        .ok_or_else(|| error::ErrorNotFound(format!("Unknown collection {}", collection_name)))?;
+    Ok(serde_json::to_string(&search_results)?)
+}
+
+#[post("/federated_search")]
+async fn federated_search(
+    state: web::Data<EmbeddingApp>,
+    federated_search: web::Json<FederatedSearch>,
+) -> Result<String> {
+    let search_results = state
+        .federated_search(federated_search.into_inner())
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    Ok(serde_json::to_string(&search_results)?)
+}
+
+#[post("/find_duplicates")]
+async fn find_duplicates(
+    state: web::Data<EmbeddingApp>,
+    find_duplicates: web::Json<FindDuplicates>,
+) -> Result<String> {
+    let find_duplicates = find_duplicates.into_inner();
+    let collection_name = find_duplicates.collection_name.clone();
+    let queued = state
+        .find_duplicates(find_duplicates)
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?;
+    if !queued {
+        return Err(error::ErrorNotFound(format!(
+            "Unknown collection {}",
+            collection_name
+        )));
+    }
+    Ok("ok".into())
+}
+
+fn duplicate_report(
+    state: &EmbeddingApp,
+    get_duplicates: &GetDuplicates,
+) -> Result<DuplicateReport> {
+    state
+        .duplicate_report(get_duplicates)
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?
+        .ok_or_else(|| {
+            error::ErrorNotFound(format!(
+                "No duplicate report for {}",
+                get_duplicates.collection_name
+            ))
+        })
+}
+
+#[post("/duplicates")]
+async fn duplicates(
+    state: web::Data<EmbeddingApp>,
+    get_duplicates: web::Json<GetDuplicates>,
+) -> Result<String> {
+    let report = duplicate_report(&state, &get_duplicates)?;
+    Ok(serde_json::to_string(&report)?)
+}
+
+/// The groups of the report as JSON lines.
+#[post("/duplicates_export")]
+async fn duplicates_export(
+    state: web::Data<EmbeddingApp>,
+    get_duplicates: web::Json<GetDuplicates>,
+) -> Result<HttpResponse> {
+    let report = duplicate_report(&state, &get_duplicates)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .body(report.to_jsonl()?))
+}
+
+#[post("/fit_pca")]
+async fn fit_pca(state: web::Data<EmbeddingApp>, fit_pca: web::Json<FitPca>) -> Result<String> {
+    let fit_pca = fit_pca.into_inner();
+    let collection_name = fit_pca.collection_name.clone();
+    let queued = state
+        .fit_pca(fit_pca)
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    if !queued {
+        return Err(error::ErrorNotFound(format!(
+            "Unknown collection {}",
+            collection_name
+        )));
+    }
+    Ok("ok".into())
+}
+
+#[post("/cluster_collection")]
+async fn cluster_collection(
+    state: web::Data<EmbeddingApp>,
+    cluster_collection: web::Json<ClusterCollection>,
+) -> Result<String> {
+    let cluster_collection = cluster_collection.into_inner();
+    let collection_name = cluster_collection.collection_name.clone();
+    let queued = state
+        .cluster_collection(cluster_collection)
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    if !queued {
+        return Err(error::ErrorNotFound(format!(
+            "Unknown collection {}",
+            collection_name
+        )));
+    }
+    Ok("ok".into())
+}
+
+#[post("/clusters")]
+async fn clusters(
+    state: web::Data<EmbeddingApp>,
+    get_clusters: web::Json<GetClusters>,
+) -> Result<String> {
+    let clusters = state
+        .clusters(&get_clusters)
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?
+        .ok_or_else(|| {
+            error::ErrorNotFound(format!("No clusters for {}", get_clusters.collection_name))
+        })?;
+    Ok(serde_json::to_string(&clusters)?)
+}
+
+#[post("/cluster_members")]
+async fn cluster_members(
+    state: web::Data<EmbeddingApp>,
+    get_cluster_members: web::Json<GetClusterMembers>,
+) -> Result<String> {
+    let members = state
+        .cluster_members(&get_cluster_members)
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?
+        .ok_or_else(|| {
+            error::ErrorNotFound(format!(
+                "No cluster {} in {}",
+                get_cluster_members.cluster, get_cluster_members.collection_name
+            ))
+        })?;
+    Ok(serde_json::to_string(&members)?)
 }
 
 #[post("/upsert_collection")]
@@ -69,7 +260,9 @@ async fn upsert_collection(
     state: web::Data<EmbeddingApp>,
     upsert_collection: web::Json<UpsertCollection>,
 ) -> Result<String> {
-    state.upsert_collection(&upsert_collection.into_inner());
+    state
+        .upsert_collection(&upsert_collection.into_inner())
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
     Ok("ok".into())
 }
 
@@ -77,16 +270,15 @@ async fn upsert_collection(
 async fn remove_collection(
     state: web::Data<EmbeddingApp>,
     remove_collection: web::Json<RemoveCollection>,
-) -> String {
-    state.remove_collection(&remove_collection.into_inner());
-    "ok".into()
+) -> Result<String> {
+    state
+        .remove_collection(&remove_collection.into_inner())
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?;
+    Ok("ok".into())
 }
 
 async fn validator(req: ServiceRequest, credentials: BearerAuth) -> Result<ServiceRequest, Error> {
-    let config = req
-        .app_data::<Config>()
-        .cloned()
-        .unwrap_or_else(Default::default);
+    let config = req.app_data::<Config>().cloned().unwrap_or_default();
 
     let app_config = req.app_data::<Data<AppConfig>>().cloned().unwrap();
 
@@ -125,8 +317,7 @@ async fn main() -> std::io::Result<()> {
     let app_config_str = read_to_string(
         matches
             .value_of("config")
-            .expect("Argument config not specified")
-            .to_string(),
+            .expect("Argument config not specified"),
     )
     .expect("Problems reading the file with configuration");
 
@@ -137,16 +328,29 @@ async fn main() -> std::io::Result<()> {
         &app_config.server_config.ip, &app_config.server_config.port
     );
 
-    println!("Visual Search listening on {:}", full_address);
-    let embedding_app = Data::new(EmbeddingApp::new(app_config.n_workers));
+    let mut embedding_app = EmbeddingApp::new(app_config.n_workers);
+    let persistence_config = app_config.persistence_config.clone();
+    if let Some(persistence_config) = &persistence_config {
+        let data_dir = PathBuf::from(&persistence_config.data_dir);
+        embedding_app
+            .recover(&data_dir)
+            .expect("Cannot restore collections");
+        embedding_app.start_snapshots(
+            data_dir,
+            Duration::from_secs(persistence_config.snapshot_interval_secs),
+        );
+    }
     embedding_app.start_workers();
+    let embedding_app = Data::new(embedding_app);
 
-    HttpServer::new(move || {
+    println!("Visual Search listening on {:}", full_address);
+    let server_app = embedding_app.clone();
+    let result = HttpServer::new(move || {
         let auth = HttpAuthentication::bearer(validator);
         App::new()
             .wrap(auth)
             .data(app_config.clone())
-            .app_data(embedding_app.clone())
+            .app_data(server_app.clone())
             .app_data(
                 web::JsonConfig::default()
                     // 10 MB limit
@@ -168,8 +372,28 @@ async fn main() -> std::io::Result<()> {
             .service(add_image)
             .service(remove_image)
             .service(search_image)
+            .service(search_similar)
+            .service(batch_search)
+            .service(range_search)
+            .service(federated_search)
+            .service(find_duplicates)
+            .service(duplicates)
+            .service(duplicates_export)
+            .service(fit_pca)
+            .service(cluster_collection)
+            .service(clusters)
+            .service(cluster_members)
     })
     .bind(full_address)?
     .run()
-    .await
+    .await;
+
+    if let Some(persistence_config) = &persistence_config {
+        println!("Saving snapshot before shutdown");
+        embedding_app
+            .save_snapshot(&PathBuf::from(&persistence_config.data_dir))
+            .expect("Cannot save snapshot");
+    }
+
+    result
 }